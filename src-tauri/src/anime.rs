@@ -1,13 +1,35 @@
-use reqwest::header::{COOKIE, HeaderValue, USER_AGENT};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
 use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use tokio::sync::{Mutex, Semaphore};
 
 use crate::Agent;
 use crate::config::CONFIG;
+use crate::resolver::Target;
+use crate::stream::{Dash, VideoStream};
+use crate::subtitle::{get_subtitles, Subtitle};
+use crate::{video, wbi};
 
-const BANGUMI_LIST_URL: &str = "https://api.bilibili.com/pgc/view/web/ep/list?ep_id=";
+const BANGUMI_LIST_URL: &str = "https://api.bilibili.com/pgc/view/web/ep/list";
+const BANGUMI_REVIEW_URL: &str = "https://api.bilibili.com/pgc/review/user";
 const BANGUMI_PLAY_URL: &str = "https://www.bilibili.com/bangumi/play/";
+// 季度信息接口，seasons 为同一系列的其他季度
+const BANGUMI_SEASON_URL: &str = "https://api.bilibili.com/pgc/view/web/season";
+// 正片中带有该角标的剧集单独作为一组
+const TRAILER_BADGE: &str = "预告";
+// 解析结果的缓存时间，避免选中剧集后添加下载时重复请求
+const STREAM_CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+// 同时解析的剧集数量，请求过多会被风控
+const MAX_CONCURRENT_RESOLVES: usize = 4;
+
+lazy_static! {
+    static ref STREAM_CACHE: Mutex<HashMap<String, (Instant, Episode)>> = Mutex::new(HashMap::new());
+    static ref RESOLVE_PERMITS: Semaphore = Semaphore::new(MAX_CONCURRENT_RESOLVES);
+}
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Anime {
@@ -21,46 +43,78 @@ pub struct Anime {
     formats: Vec<String>,
     episodes: Vec<Episode>,
     count: usize,
+    // 正片以外的 PV、OP/ED 和特别篇等分组，以及预告
+    sections: Vec<Section>,
+    // 同一系列的其他季度
+    seasons: Vec<Season>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Section {
+    title: String,
+    episodes: Vec<Episode>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct Season {
+    season_id: String,
+    media_id: String,
+    title: String,
+    cover: String,
+    badge: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Episode {
     bvid: String,
     ep_id: String,
     cid: String,
     title: String,
-    video_urls: Vec<String>,
-    audio_url: String,
+    // dash 中的视频流，包括清晰度、编码和备用 CDN 链接
+    pub(crate) video_streams: Vec<VideoStream>,
+    pub(crate) audio_url: String,
+    pub(crate) audio_backup_urls: Vec<String>,
+    // 仅下载音频时使用的最高音质音频流，格式为 flac 或 m4a
+    pub(crate) best_audio_url: String,
+    pub(crate) best_audio_backup_urls: Vec<String>,
+    pub(crate) best_audio_format: String,
+    // CC 字幕列表，包括语言代码和字幕文件链接
+    pub(crate) subtitles: Vec<Subtitle>,
     duration: i32,
     cover: String,
     play: String,
     danmaku: String,
+    // 与 video_streams 一一对应的估算大小，按码率和时长计算，不是实际的文件大小
     sizes: Vec<String>,
 }
 
-pub async fn get_anime_info(id: &str) -> Result<Anime, String> {
+// 支持剧集、季度和条目编号，条目编号先转换为季度编号
+pub async fn get_anime_info(target: &Target) -> Result<Anime, String> {
     let surf_client = surf::client();
-    let ep_id = &check_ep_id(id).await;
-
-    let config = CONFIG.lock().unwrap();
-    let cookie = config.cookie.clone();
+    let (play_id, param) = match target {
+        Target::Episode { ep_id } => (format!("ep{}", ep_id), ("ep_id", ep_id.to_string())),
+        Target::Season { season_id } => (format!("ss{}", season_id), ("season_id", season_id.to_string())),
+        Target::Media { media_id } => {
+            let season_id = get_media_season(*media_id).await?;
+            (format!("ss{}", season_id), ("season_id", season_id.to_string()))
+        }
+        _ => return Err(format!("不是番剧的链接: {:?}", target)),
+    };
 
-    let client = reqwest::Client::new();
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(USER_AGENT, HeaderValue::from_static(Agent));
-    headers.insert(COOKIE, HeaderValue::from_str(&cookie).unwrap());
+    let cookie = CONFIG.lock().unwrap().cookie.clone();
 
-    let bangumi_play_url = format!("{}{}", BANGUMI_PLAY_URL, ep_id);
-    let bangumi_list_url = format!("{}{}", BANGUMI_LIST_URL, ep_id.replace("ep", ""));
+    let bangumi_play_url = format!("{}{}", BANGUMI_PLAY_URL, play_id);
 
     // 发送带 Cookie 的请求
-    let mut html = surf_client
+    let html = surf_client
         .get(&bangumi_play_url)
         .header("Cookie", cookie.as_str())
         .header("User-Agent", Agent)
-        .await.unwrap()
+        .await
+        .map_err(|e| format!("request {} failed: {}", play_id, e))?
         .body_string()
-        .await.unwrap();
+        .await
+        .map_err(|e| format!("read {} failed: {}", play_id, e))?;
 
     let document = Html::parse_document(&html);
 
@@ -75,115 +129,225 @@ pub async fn get_anime_info(id: &str) -> Result<Anime, String> {
         formats: Vec::new(),
         episodes: Vec::new(),
         count: 0,
+        sections: Vec::new(),
+        seasons: Vec::new(),
     };
 
     let pattern = regex::Regex::new(r#"<script id="__NEXT_DATA__" type="application/json">([^<]*)</script>"#).unwrap();
-    if let Some(caps) = pattern.captures(&html) {
-        if let Some(matched) = caps.get(1) {
-            let json: Value = serde_json::from_str(matched.as_str()).unwrap();
-
-            let animates = &json["props"]["pageProps"]["dehydratedState"]["queries"][0]["state"]["data"]["result"]["video_info"];
-            let selector = Selector::parse("div[class=\"mediainfo_mediaDesc__jjRiB\"] span").unwrap();
-
-            anime.types = document.select(&selector).nth(0).map(|s| s.text().collect()).unwrap_or_default();
-            anime.score = document.select(&Selector::parse("div[class=\"mediainfo_score__SQ_KG\"]").unwrap())
-                .next().map(|e| e.text().collect()).unwrap_or_default();
-            anime.title = document.select(&Selector::parse("meta[property=\"og:title\"]").unwrap())
-                .next().map(|e| e.value().attr("content").unwrap_or_default().to_string()).unwrap_or_default();
-            anime.play = document.select(&Selector::parse("div[class=\"mediainfo_mediaDesc__jjRiB\"]").unwrap())
-                .next().map(|e| e.text().collect()).unwrap_or_default();
-            anime.description = document.select(&Selector::parse("meta[name=\"description\"]").unwrap())
-                .next().map(|e| e.value().attr("content").unwrap_or_default().to_string()).unwrap_or_default();
-            anime.date = document.select(&selector).nth(1).map(|s| s.text().collect()).unwrap_or_default();
-
-            let url = document.select(&Selector::parse("a[class=\"mediainfo_mediaCover__pm26q empty\"]").unwrap())
-                .next().map(|e| e.value().attr("href").unwrap_or_default().to_string()).unwrap_or_default();
-            let cover_request_url = format!("https://api.bilibili.com/pgc/review/user?media_id={}", url[(url.find("md").unwrap() + 2)..].chars().take_while(|c| c.is_digit(10)).collect::<String>());
-            let response1 = client.get(&cover_request_url).send().await.unwrap();
-            let result: Value = serde_json::from_str(&response1.text().await.unwrap()).unwrap();
-            anime.cover = result["result"]["media"]["cover"].to_string().trim_matches('"').to_string();
-
-            for format in animates["support_formats"].as_array().unwrap_or(&Vec::new()) {
-                anime.formats.push(format.get("description").unwrap().to_string().trim_matches('"').to_string());
-            }
+    // 页面中没有 __NEXT_DATA__ 时通常是地区限制或者需要登录，不返回空的结果
+    let matched = pattern.captures(&html)
+        .and_then(|caps| caps.get(1))
+        .ok_or_else(|| format!("no __NEXT_DATA__ in {}", play_id))?;
+    let json: Value = serde_json::from_str(matched.as_str())
+        .map_err(|e| format!("parse {} failed: {}", play_id, e))?;
 
-            let response1 = client.get(&bangumi_list_url).headers(headers.clone()).send().await.unwrap();
-            let result: Value = serde_json::from_str(&response1.text().await.unwrap()).unwrap();
-            let ep_list = result["result"]["episodes"].as_array().unwrap();
+    let animates = &json["props"]["pageProps"]["dehydratedState"]["queries"][0]["state"]["data"]["result"]["video_info"];
+    if animates.is_null() {
+        return Err(format!("no video info in {}", play_id));
+    }
+    let selector = Selector::parse("div[class=\"mediainfo_mediaDesc__jjRiB\"] span").unwrap();
 
-            let mut episodes = Vec::new();
-            for ep in ep_list {
-                if ep["badge"].as_str().unwrap_or_default() == "\"预告\"" {
-                    continue;
-                }
+    anime.types = document.select(&selector).nth(0).map(|s| s.text().collect()).unwrap_or_default();
+    anime.score = document.select(&Selector::parse("div[class=\"mediainfo_score__SQ_KG\"]").unwrap())
+        .next().map(|e| e.text().collect()).unwrap_or_default();
+    anime.title = document.select(&Selector::parse("meta[property=\"og:title\"]").unwrap())
+        .next().map(|e| e.value().attr("content").unwrap_or_default().to_string()).unwrap_or_default();
+    anime.play = document.select(&Selector::parse("div[class=\"mediainfo_mediaDesc__jjRiB\"]").unwrap())
+        .next().map(|e| e.text().collect()).unwrap_or_default();
+    anime.description = document.select(&Selector::parse("meta[name=\"description\"]").unwrap())
+        .next().map(|e| e.value().attr("content").unwrap_or_default().to_string()).unwrap_or_default();
+    anime.date = document.select(&selector).nth(1).map(|s| s.text().collect()).unwrap_or_default();
 
-                let mut episode = Episode {
-                    bvid: ep["bvid"].as_str().unwrap_or_default().to_string(),
-                    ep_id: ep["ep_id"].as_i64().map(|num| num.to_string()).unwrap_or_default().to_string(),
-                    cover: ep["cover"].as_str().unwrap_or_default().to_string(),
-                    duration: ep["duration"].as_i64().map(|num| num.to_string()).unwrap_or_default().parse().unwrap_or_default(),
-                    title: ep["long_title"].as_str().unwrap_or_default().to_string(),
-                    play: ep["stat_for_unity"]["vt"]["text"].as_str().unwrap_or_default().to_string(),
-                    danmaku: ep["stat_for_unity"]["danmaku"]["text"].as_str().unwrap_or_default().to_string(),
-                    cid: ep["cid"].as_i64().map(|num| num.to_string()).unwrap_or_default().to_string(),
-                    sizes: Vec::new(),
-                    video_urls: Vec::new(),
-                    audio_url: String::new(),
-                };
-
-                let mut html1 = surf_client
-                    .get(&&format!("{}ep{}", BANGUMI_PLAY_URL, episode.ep_id))
-                    .header("Cookie", cookie.as_str())
-                    .header("User-Agent", Agent)
-                    .await.unwrap()
-                    .body_string()
-                    .await.unwrap();
-                if let Some(caps1) = pattern.captures(&html1) {
-                    if let Some(matched1) = caps1.get(1) {
-                        let json1: Value = serde_json::from_str(matched1.as_str()).unwrap();
-                        let videos = &json1["props"]["pageProps"]["dehydratedState"]["queries"][0]["state"]["data"]["result"]["video_info"]["dash"]["video"];
-                        let audios = &json1["props"]["pageProps"]["dehydratedState"]["queries"][0]["state"]["data"]["result"]["video_info"]["dash"]["audio"];
-
-                        for video in videos.as_array().unwrap_or(&Vec::new()) {
-                            episode.video_urls.push(video["base_url"].as_str().unwrap_or_default().to_string());
-                            episode.sizes.push(video["size"].as_i64().unwrap_or_default().to_string());
-                        }
-
-                        for audio in audios.as_array().unwrap_or(&Vec::new()) {
-                            episode.audio_url = audio["base_url"].as_str().unwrap_or_default().to_string();
-                            break;
-                        }
-                    }
-                }
+    let url = document.select(&Selector::parse("a[class=\"mediainfo_mediaCover__pm26q empty\"]").unwrap())
+        .next().map(|e| e.value().attr("href").unwrap_or_default().to_string()).unwrap_or_default();
+    let media_start = url.find("md").ok_or_else(|| format!("no media id in {}", play_id))? + 2;
+    let media_id = url[media_start..].chars().take_while(|c| c.is_ascii_digit()).collect::<String>();
+    if media_id.is_empty() {
+        return Err(format!("no media id in {}", play_id));
+    }
+    let result = wbi::get_json(BANGUMI_REVIEW_URL, &[("media_id", media_id)]).await?;
+    anime.cover = result["result"]["media"]["cover"].to_string().trim_matches('"').to_string();
 
-                episodes.push(episode);
-            }
+    for format in animates["support_formats"].as_array().unwrap_or(&Vec::new()) {
+        anime.formats.push(format["description"].as_str().unwrap_or_default().to_string());
+    }
 
-            anime.episodes = episodes;
-            anime.count = anime.episodes.len();
+    let result = wbi::get_json(BANGUMI_LIST_URL, &[param.clone()]).await?;
+    let ep_list = result["result"]["episodes"].as_array()
+        .ok_or_else(|| format!("failed to get episodes of {}: {} {}", play_id, result["code"], result["message"]))?;
+
+    // 预告不再混在正片中，单独作为一组
+    let (trailers, episodes): (Vec<&Value>, Vec<&Value>) = ep_list.iter()
+        .partition(|ep| ep["badge"].as_str().unwrap_or_default() == TRAILER_BADGE);
+    anime.episodes = episodes.into_iter().map(parse_episode).collect();
+    if !trailers.is_empty() {
+        anime.sections.push(Section {
+            title: TRAILER_BADGE.to_string(),
+            episodes: trailers.into_iter().map(parse_episode).collect(),
+        });
+    }
+    for section in result["result"]["section"].as_array().unwrap_or(&Vec::new()) {
+        let episodes: Vec<Episode> = section["episodes"].as_array().unwrap_or(&Vec::new())
+            .iter()
+            .map(parse_episode)
+            .collect();
+        if !episodes.is_empty() {
+            anime.sections.push(Section {
+                title: section["title"].as_str().unwrap_or_default().to_string(),
+                episodes,
+            });
         }
     }
 
+    // 获取系列的其他季度失败时不影响剧集列表
+    match get_related_seasons(param).await {
+        Ok(seasons) => anime.seasons = seasons,
+        Err(e) => eprintln!("Failed to get related seasons of {}: {}", play_id, e),
+    }
+
+    anime.count = anime.episodes.len();
+
     Ok(anime)
 }
 
-pub async fn check_ep_id(ep_id: &str) -> String {
-    if !ep_id.starts_with("http") {
-        return ep_id.to_string();
+// 剧集列表中的一集，音视频链接在选中或者添加下载时再解析
+fn parse_episode(ep: &Value) -> Episode {
+    Episode {
+        bvid: ep["bvid"].as_str().unwrap_or_default().to_string(),
+        // 部分分组中的剧集只有 id
+        ep_id: ep["ep_id"].as_i64().or_else(|| ep["id"].as_i64()).map(|num| num.to_string()).unwrap_or_default(),
+        cover: ep["cover"].as_str().unwrap_or_default().to_string(),
+        duration: ep["duration"].as_i64().map(|num| num.to_string()).unwrap_or_default().parse().unwrap_or_default(),
+        // PV 和 OP/ED 等没有 long_title，使用 title
+        title: ep["long_title"].as_str().filter(|title| !title.is_empty())
+            .or_else(|| ep["title"].as_str())
+            .unwrap_or_default()
+            .to_string(),
+        play: ep["stat_for_unity"]["vt"]["text"].as_str().unwrap_or_default().to_string(),
+        danmaku: ep["stat_for_unity"]["danmaku"]["text"].as_str().unwrap_or_default().to_string(),
+        cid: ep["cid"].as_i64().map(|num| num.to_string()).unwrap_or_default().to_string(),
+        ..Default::default()
     }
+}
 
-    let surf_client = surf::client();
-    let mut html = surf_client
-        .get(ep_id)
+// 条目编号对应的季度编号
+async fn get_media_season(media_id: u64) -> Result<u64, String> {
+    let result = wbi::get_json(BANGUMI_REVIEW_URL, &[("media_id", media_id.to_string())]).await?;
+    result["result"]["media"]["season_id"].as_u64()
+        .ok_or_else(|| format!("failed to get season of md{}: {} {}", media_id, result["code"], result["message"]))
+}
+
+// 同一系列的所有季度，包括当前季度
+async fn get_related_seasons(param: (&str, String)) -> Result<Vec<Season>, String> {
+    let result = wbi::get_json(BANGUMI_SEASON_URL, &[param]).await?;
+    if result["code"].as_i64() != Some(0) {
+        return Err(format!("{} {}", result["code"], result["message"]));
+    }
+
+    let seasons = result["result"]["seasons"].as_array().unwrap_or(&Vec::new())
+        .iter()
+        .map(|season| Season {
+            season_id: season["season_id"].as_i64().map(|num| num.to_string()).unwrap_or_default(),
+            media_id: season["media_id"].as_i64().map(|num| num.to_string()).unwrap_or_default(),
+            title: season["season_title"].as_str().unwrap_or_default().to_string(),
+            cover: season["cover"].as_str().unwrap_or_default().to_string(),
+            badge: season["badge"].as_str().unwrap_or_default().to_string(),
+        })
+        .collect();
+    Ok(seasons)
+}
+
+// 从剧集页面中解析音视频链接
+async fn fill_episode_streams(surf_client: &surf::Client, cookie: &str, episode: &mut Episode) -> Result<(), String> {
+    let pattern = regex::Regex::new(r#"<script id="__NEXT_DATA__" type="application/json">([^<]*)</script>"#).unwrap();
+    let html1 = surf_client
+        .get(&format!("{}ep{}", BANGUMI_PLAY_URL, episode.ep_id))
+        .header("Cookie", cookie)
         .header("User-Agent", Agent)
-        .await.unwrap()
+        .await
+        .map_err(|e| format!("request ep{} failed: {}", episode.ep_id, e))?
         .body_string()
-        .await.unwrap();
+        .await
+        .map_err(|e| format!("read ep{} failed: {}", episode.ep_id, e))?;
+    if let Some(caps1) = pattern.captures(&html1) {
+        if let Some(matched1) = caps1.get(1) {
+            let json1: Value = serde_json::from_str(matched1.as_str())
+                .map_err(|e| format!("parse ep{} failed: {}", episode.ep_id, e))?;
+            let dash: Dash = serde_json::from_value(json1["props"]["pageProps"]["dehydratedState"]["queries"][0]["state"]["data"]["result"]["video_info"]["dash"].clone())
+                .unwrap_or_default();
 
-    let document = Html::parse_document(&html);
-    let body = document.select(&Selector::parse("div[class=\"toolbar\"]").unwrap())
-        .next().map(|e| e.value().attr("mr-show").unwrap_or_default().to_string()).unwrap_or_default();
-    let json: Value = serde_json::from_str(body.as_str()).unwrap();
+            // 和普通视频使用相同的选流规则
+            let mut streams = video::Episode::default();
+            video::fill_streams(&dash, &mut streams);
+            episode.video_streams = streams.video_streams;
+            episode.sizes = streams.sizes;
+            episode.audio_url = streams.audio_url;
+            episode.audio_backup_urls = streams.audio_backup_urls;
+            episode.best_audio_url = streams.best_audio_url;
+            episode.best_audio_backup_urls = streams.best_audio_backup_urls;
+            episode.best_audio_format = streams.best_audio_format;
+        }
+    }
+
+    Ok(())
+}
+
+// 根据 ep_id 获取剧集的音视频链接，短时间内重复获取时使用缓存，同时解析的数量有上限
+pub async fn get_episode_streams(ep_id: &str) -> Result<Episode, String> {
+    let ep_id = ep_id.trim_start_matches("ep").to_string();
+    if let Some((fetched, episode)) = STREAM_CACHE.lock().await.get(&ep_id) {
+        if fetched.elapsed() < STREAM_CACHE_TTL {
+            return Ok(episode.clone());
+        }
+    }
 
-    return format!("ep{}", json["msg"]["ep_id"]);
+    let _permit = RESOLVE_PERMITS.acquire().await.map_err(|e| e.to_string())?;
+    let cookie = CONFIG.lock().unwrap().cookie.clone();
+    let mut episode = Episode {
+        ep_id: ep_id.clone(),
+        ..Default::default()
+    };
+
+    fill_episode_streams(&surf::client(), &cookie, &mut episode).await?;
+    if episode.video_streams.is_empty() && episode.audio_url.is_empty() {
+        return Err(format!("no dash streams for ep{}", episode.ep_id));
+    }
+
+    let mut cache = STREAM_CACHE.lock().await;
+    cache.retain(|_, (fetched, _)| fetched.elapsed() < STREAM_CACHE_TTL);
+    cache.insert(ep_id, (Instant::now(), episode.clone()));
+    Ok(episode)
+}
+
+// 需要解析的剧集，bvid 和 cid 用于获取字幕列表
+#[derive(Debug, Deserialize)]
+pub struct EpisodeRef {
+    ep_id: String,
+    #[serde(default)]
+    bvid: String,
+    #[serde(default)]
+    cid: String,
+}
+
+// 并行解析多个剧集的音视频链接和字幕，结果和 episodes 的顺序一致
+pub async fn resolve_episodes(episodes: Vec<EpisodeRef>) -> Vec<Result<Episode, String>> {
+    let tasks = episodes
+        .into_iter()
+        .map(|episode| tokio::spawn(async move {
+            let mut resolved = get_episode_streams(&episode.ep_id).await?;
+            if !episode.bvid.is_empty() && !episode.cid.is_empty() {
+                match get_subtitles(&episode.bvid, &episode.cid).await {
+                    Ok(subtitles) => resolved.subtitles = subtitles,
+                    Err(e) => eprintln!("Failed to get subtitles of ep{}: {}", episode.ep_id, e),
+                }
+            }
+            Ok(resolved)
+        }))
+        .collect::<Vec<_>>();
+
+    let mut episodes = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        episodes.push(task.await.unwrap_or_else(|e| Err(e.to_string())));
+    }
+    episodes
 }
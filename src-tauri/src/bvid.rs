@@ -0,0 +1,169 @@
+// av 号和 BV 号的相互转换
+// 2023 年起 av 号超过 2^30 后使用新的 64 位算法，旧算法只能表示较小的 av 号，二者在旧算法的范围内结果相同
+
+const PREFIX: &str = "BV1";
+const BV_LEN: usize = 12;
+const BASE: u64 = 58;
+
+// 当前算法：av 号与 2^51 合并后异或，再按 58 进制写入后 9 位并交换其中两组字符
+const ALPHABET: &[u8; 58] = b"FcwAPNKTMug3GV5Lj7EJnHpWsx4tb8haYeviqBz6rkCy12mUSDQX9RdoZf";
+const XOR_CODE: u64 = 23442827791579;
+const MASK_CODE: u64 = (1 << 51) - 1;
+const MAX_AID: u64 = 1 << 51;
+
+// 旧算法：异或并加上偏移后按 58 进制写入固定的 6 个位置，其余位置为固定字符
+const LEGACY_ALPHABET: &[u8; 58] = b"fZodR9XQDSUm21yCkr6zBqiveYah8bt4xsWpHnJE7jL5VG3guMTKNPAwcF";
+const LEGACY_POSITIONS: [usize; 6] = [11, 10, 3, 8, 4, 6];
+const LEGACY_TEMPLATE: &[u8; 12] = b"BV1  4 1 7  ";
+const LEGACY_XOR: u64 = 177451812;
+const LEGACY_ADD: u64 = 8728348608;
+// 旧算法能表示的最大 av 号
+const LEGACY_MAX_AID: u64 = (1 << 30) - 1;
+
+// av 号转换为 BV 号，旧算法范围内的 av 号使用旧算法
+pub fn av_to_bv(aid: u64) -> Result<String, String> {
+    if aid == 0 || aid >= MAX_AID {
+        return Err(format!("av 号超出范围: {}", aid));
+    }
+    if aid <= LEGACY_MAX_AID {
+        return Ok(legacy_av_to_bv(aid));
+    }
+
+    let mut bytes = [0u8; BV_LEN];
+    bytes[..3].copy_from_slice(PREFIX.as_bytes());
+    let mut tmp = (MAX_AID | aid) ^ XOR_CODE;
+    for i in (3..BV_LEN).rev() {
+        bytes[i] = ALPHABET[(tmp % BASE) as usize];
+        tmp /= BASE;
+    }
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+// BV 号转换为 av 号，不区分开头 BV 的大小写
+// 旧算法生成的 BV 号重新编码后一致时使用旧算法，两种算法在该范围内结果相同
+pub fn bv_to_av(bvid: &str) -> Result<u64, String> {
+    let bytes = bvid.as_bytes();
+    if bytes.len() != BV_LEN || !bytes[..3].eq_ignore_ascii_case(PREFIX.as_bytes()) {
+        return Err(format!("无效的 BV 号: {}", bvid));
+    }
+    if let Ok(aid) = legacy_bv_to_av(bvid) {
+        if aid > 0 && aid <= LEGACY_MAX_AID && legacy_av_to_bv(aid)[3..] == bvid[3..] {
+            return Ok(aid);
+        }
+    }
+
+    let mut bytes: [u8; BV_LEN] = bytes.try_into().unwrap();
+    bytes.swap(3, 9);
+    bytes.swap(4, 7);
+    let mut tmp = 0u64;
+    for c in &bytes[3..] {
+        let index = ALPHABET.iter().position(|a| a == c).ok_or_else(|| format!("无效的 BV 号: {}", bvid))?;
+        tmp = tmp * BASE + index as u64;
+    }
+    let aid = (tmp & MASK_CODE) ^ XOR_CODE;
+    if aid == 0 {
+        return Err(format!("无效的 BV 号: {}", bvid));
+    }
+    Ok(aid)
+}
+
+fn legacy_av_to_bv(aid: u64) -> String {
+    let mut x = (aid ^ LEGACY_XOR) + LEGACY_ADD;
+    let mut bytes = *LEGACY_TEMPLATE;
+    for position in LEGACY_POSITIONS {
+        bytes[position] = LEGACY_ALPHABET[(x % BASE) as usize];
+        x /= BASE;
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+fn legacy_bv_to_av(bvid: &str) -> Result<u64, String> {
+    let bytes = bvid.as_bytes();
+    if bytes.len() != BV_LEN {
+        return Err(format!("无效的 BV 号: {}", bvid));
+    }
+    let mut x = 0u64;
+    for position in LEGACY_POSITIONS.iter().rev() {
+        let index = LEGACY_ALPHABET.iter().position(|a| *a == bytes[*position])
+            .ok_or_else(|| format!("无效的 BV 号: {}", bvid))?;
+        x = x * BASE + index as u64;
+    }
+    x.checked_sub(LEGACY_ADD)
+        .map(|x| x ^ LEGACY_XOR)
+        .ok_or_else(|| format!("无效的 BV 号: {}", bvid))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 旧算法范围内的 av 号和 BV 号
+    const LEGACY_PAIRS: [(u64, &str); 6] = [
+        (1, "BV1xx411c7mQ"),
+        (2, "BV1xx411c7mD"),
+        (170001, "BV17x411w7KC"),
+        (455017605, "BV1Q541167Qg"),
+        (882584971, "BV1mK4y1C7Bz"),
+        (1054803170, "BV1mH4y1u7UA"),
+    ];
+
+    // 超过 2^30 只能用新算法表示的 av 号
+    const PAIRS: [(u64, &str); 1] = [(111298867365120, "BV1L9Uoa9EUx")];
+
+    #[test]
+    fn converts_legacy_pairs() {
+        for (aid, bvid) in LEGACY_PAIRS {
+            assert_eq!(legacy_av_to_bv(aid), bvid);
+            assert_eq!(legacy_bv_to_av(bvid), Ok(aid));
+            assert_eq!(av_to_bv(aid).as_deref(), Ok(bvid));
+            assert_eq!(bv_to_av(bvid), Ok(aid));
+        }
+    }
+
+    #[test]
+    fn converts_large_aids() {
+        for (aid, bvid) in PAIRS {
+            assert!(aid > LEGACY_MAX_AID);
+            assert_eq!(av_to_bv(aid).as_deref(), Ok(bvid));
+            assert_eq!(bv_to_av(bvid), Ok(aid));
+        }
+    }
+
+    #[test]
+    fn round_trips_around_legacy_limit() {
+        for aid in [LEGACY_MAX_AID - 1, LEGACY_MAX_AID, LEGACY_MAX_AID + 1, MAX_AID - 1] {
+            let bvid = av_to_bv(aid).unwrap();
+            assert_eq!(bv_to_av(&bvid), Ok(aid), "{}", bvid);
+        }
+    }
+
+    #[test]
+    fn accepts_lowercase_prefix() {
+        assert_eq!(bv_to_av("bv17x411w7KC"), Ok(170001));
+    }
+
+    #[test]
+    fn rejects_malformed_bvids() {
+        for bvid in [
+            "",
+            "BV1xx411c7m",
+            "BV1xx411c7mQQ",
+            "BV1xx411c7m0",
+            "BV1xx411c7mI",
+            "BV1xx411c7mO",
+            "BV1xx411c7ml",
+            "AV1xx411c7mQ",
+            "BV2xx411c7mQ",
+        ] {
+            assert!(bv_to_av(bvid).is_err(), "{}", bvid);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_aids() {
+        assert!(av_to_bv(0).is_err());
+        assert!(av_to_bv(MAX_AID).is_err());
+    }
+}
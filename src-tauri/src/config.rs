@@ -2,19 +2,224 @@ use std::fs::{File, OpenOptions};
 use std::io::{BufReader, Write};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use chrono::{Datelike, NaiveDateTime, NaiveTime};
 use dirs_next::download_dir;
 use lazy_static::lazy_static;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
 use serde_json::Result;
 
+use crate::queue::resize_concurrency;
+use crate::limiter::GLOBAL_LIMITER;
 use crate::path::get_path_str;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BiliConfig {
     pub(crate) cookie: String,
     pub(crate) agent: String,
     pub(crate) save_path: String,
+    // 单个音视频流拆分的分段数，即同时使用的连接数
+    #[serde(default = "default_segment_count")]
+    pub(crate) segment_count: usize,
+    // 下载速度持续低于该值（KB/s）时切换到备用 CDN，0 表示不限制
+    #[serde(default = "default_mirror_min_speed")]
+    pub(crate) mirror_min_speed: i64,
+    #[serde(default)]
+    pub(crate) retry: RetryPolicy,
+    // 所有任务合计的下载速度上限（KB/s），0 表示不限速
+    #[serde(default)]
+    pub(crate) speed_limit: i64,
+    // 同时下载的任务数
+    #[serde(default = "default_max_concurrent")]
+    pub(crate) max_concurrent: usize,
+    #[serde(default)]
+    pub(crate) schedule: Schedule,
+    // 磁盘剩余空间低于该值（MB）时暂停所有任务，添加任务时也需要预留
+    #[serde(default = "default_min_free_space")]
+    pub(crate) min_free_space: i64,
+    // 用于合并音视频的 ffmpeg 路径，为空时使用内置封装或自动查找
+    #[serde(default)]
+    pub(crate) ffmpeg_path: String,
+    // 新任务默认的封装格式，mp4 或 mkv
+    #[serde(default = "default_container")]
+    pub(crate) container: String,
+    // 是否写入标题、作者、日期、简介和封面，写入时每个任务都需要合并一次
+    #[serde(default)]
+    pub(crate) embed_metadata: bool,
+    #[serde(default)]
+    pub(crate) quality: QualityPreference,
+    #[serde(default)]
+    pub(crate) danmaku: DanmakuConfig,
+    #[serde(default)]
+    pub(crate) subtitle: SubtitleConfig,
+}
+
+// 自动选择视频流的规则，max_height 为 0 时不限制分辨率，codecs 中靠前的编码优先
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QualityPreference {
+    pub(crate) max_height: i64,
+    pub(crate) codecs: Vec<String>,
+}
+
+impl Default for QualityPreference {
+    fn default() -> Self {
+        QualityPreference {
+            max_height: 0,
+            codecs: vec!["avc".to_string(), "hevc".to_string(), "av1".to_string()],
+        }
+    }
+}
+
+// 下载完成后保存弹幕，以及转换为 ASS 字幕时的样式
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DanmakuConfig {
+    pub(crate) enabled: bool,
+    // 通过 seg.so 分段获取全部弹幕，否则只保存 XML 接口返回的部分
+    pub(crate) full: bool,
+    pub(crate) ass: bool,
+    // 标准字号的弹幕在 1080P 画面中的字体大小
+    pub(crate) font_size: i64,
+    // 不透明度，取值 0 到 1
+    pub(crate) opacity: f64,
+    // 弹幕占用的屏幕高度比例，放不下的弹幕会被丢弃
+    pub(crate) density: f64,
+    // 滚动弹幕从右到左经过屏幕的秒数
+    pub(crate) scroll_duration: f64,
+}
+
+impl Default for DanmakuConfig {
+    fn default() -> Self {
+        DanmakuConfig {
+            enabled: false,
+            full: false,
+            ass: true,
+            font_size: 48,
+            opacity: 0.8,
+            density: 0.5,
+            scroll_duration: 8.0,
+        }
+    }
+}
+
+// 下载 CC 字幕时保存的格式，embed 为合并时同时写入软字幕轨道
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SubtitleConfig {
+    pub(crate) srt: bool,
+    pub(crate) vtt: bool,
+    pub(crate) embed: bool,
+}
+
+impl Default for SubtitleConfig {
+    fn default() -> Self {
+        SubtitleConfig {
+            srt: true,
+            vtt: false,
+            embed: false,
+        }
+    }
+}
+
+// 允许下载的时间段，未启用时任何时间都可以下载
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Schedule {
+    pub(crate) enabled: bool,
+    pub(crate) windows: Vec<TimeWindow>,
+}
+
+// weekday 从周一的 0 到周日的 6，时间格式为 HH:MM，结束早于开始时表示跨越午夜
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimeWindow {
+    pub(crate) weekday: u32,
+    pub(crate) start: String,
+    pub(crate) end: String,
+}
+
+impl Schedule {
+    pub fn is_open(&self, now: NaiveDateTime) -> bool {
+        if !self.enabled || self.windows.is_empty() {
+            return true;
+        }
+        let weekday = now.weekday().num_days_from_monday();
+        self.windows.iter().any(|window| window.contains(weekday, now.time()))
+    }
+}
+
+impl TimeWindow {
+    fn contains(&self, weekday: u32, time: NaiveTime) -> bool {
+        let (start, end) = match (NaiveTime::parse_from_str(&self.start, "%H:%M"), NaiveTime::parse_from_str(&self.end, "%H:%M")) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return false,
+        };
+
+        if start <= end {
+            self.weekday == weekday && start <= time && time < end
+        } else {
+            // 跨越午夜的时间段属于开始的那一天
+            (self.weekday == weekday && time >= start) || ((self.weekday + 1) % 7 == weekday && time < end)
+        }
+    }
+}
+
+fn default_max_concurrent() -> usize {
+    3
+}
+
+fn default_min_free_space() -> i64 {
+    1024
+}
+
+fn default_container() -> String {
+    "mp4".to_string()
+}
+
+// 下载出错后的重试策略
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RetryPolicy {
+    // 最多尝试的次数，包括第一次
+    pub(crate) max_attempts: u32,
+    // 第一次重试前等待的毫秒数，之后每次翻倍
+    pub(crate) base_delay: u64,
+    pub(crate) max_delay: u64,
+    // 随机抖动占等待时间的比例，取值 0 到 1
+    pub(crate) jitter: f64,
+    // 可以重试的 HTTP 状态码
+    pub(crate) retry_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: 1000,
+            max_delay: 60000,
+            jitter: 0.3,
+            retry_statuses: vec![408, 429, 500, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    // 指数退避，加入随机抖动避免多个任务同时重试
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self.base_delay
+            .saturating_mul(1 << attempt.saturating_sub(1).min(20))
+            .min(self.max_delay);
+        let jitter = (delay as f64 * self.jitter.clamp(0.0, 1.0)) as u64;
+        if jitter == 0 {
+            return Duration::from_millis(delay);
+        }
+        Duration::from_millis(delay - jitter + rand::thread_rng().gen_range(0..=jitter * 2))
+    }
+}
+
+fn default_segment_count() -> usize {
+    4
+}
+
+fn default_mirror_min_speed() -> i64 {
+    20
 }
 
 lazy_static! {
@@ -26,6 +231,19 @@ pub fn create_default_config() -> BiliConfig {
         cookie: "".to_string(),
         agent: "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36 Edg/126.0.0.0".to_string(),
         save_path: download_dir().unwrap().to_str().unwrap().parse().unwrap(),
+        segment_count: default_segment_count(),
+        mirror_min_speed: default_mirror_min_speed(),
+        retry: RetryPolicy::default(),
+        speed_limit: 0,
+        max_concurrent: default_max_concurrent(),
+        schedule: Schedule::default(),
+        min_free_space: default_min_free_space(),
+        ffmpeg_path: String::new(),
+        container: default_container(),
+        embed_metadata: false,
+        quality: QualityPreference::default(),
+        danmaku: DanmakuConfig::default(),
+        subtitle: SubtitleConfig::default(),
     }
 }
 
@@ -57,10 +275,28 @@ pub fn save_config(config: BiliConfig) -> std::io::Result<()> {
     let config_data = serde_json::to_string_pretty(&config)?;
     file.set_len(0)?; // 清空文件
     file.write_all(config_data.as_bytes())?;
-    let mut old_config = CONFIG.lock().unwrap();
-    old_config.save_path = config.save_path;
-    old_config.cookie = config.cookie;
-    old_config.agent = config.agent;
+    {
+        let mut old_config = CONFIG.lock().unwrap();
+        old_config.save_path = config.save_path;
+        old_config.cookie = config.cookie;
+        old_config.agent = config.agent;
+        old_config.segment_count = config.segment_count;
+        old_config.mirror_min_speed = config.mirror_min_speed;
+        old_config.retry = config.retry;
+        old_config.speed_limit = config.speed_limit;
+        old_config.max_concurrent = config.max_concurrent;
+        old_config.schedule = config.schedule;
+        old_config.min_free_space = config.min_free_space;
+        old_config.ffmpeg_path = config.ffmpeg_path;
+        old_config.container = config.container;
+        old_config.embed_metadata = config.embed_metadata;
+        old_config.quality = config.quality;
+        old_config.danmaku = config.danmaku;
+        old_config.subtitle = config.subtitle;
+    }
+    // 限速和并发数立即对正在下载的任务生效
+    GLOBAL_LIMITER.set_rate(config.speed_limit * 1024);
+    resize_concurrency(config.max_concurrent);
     Ok(())
 }
 
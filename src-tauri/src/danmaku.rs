@@ -0,0 +1,400 @@
+use std::collections::HashSet;
+use std::fs;
+
+use regex::Regex;
+
+use crate::Agent;
+use crate::config::{CONFIG, DanmakuConfig};
+use crate::path::get_sidecar_file_path;
+use crate::wbi::api_headers;
+
+// XML 接口只返回部分弹幕，数量上限与视频时长有关
+const DANMU_URL: &str = "https://api.bilibili.com/x/v1/dm/list.so?oid=";
+// 弹幕元数据，其中包含分段数量
+const DANMAKU_VIEW_URL: &str = "https://api.bilibili.com/x/v2/dm/web/view";
+// 按六分钟一段获取全部弹幕，返回 protobuf
+const DANMAKU_SEG_URL: &str = "https://api.bilibili.com/x/v2/dm/web/seg.so";
+
+// ASS 画布大小，播放器会按视频实际分辨率缩放
+const PLAY_RES_X: f64 = 1920.0;
+const PLAY_RES_Y: f64 = 1080.0;
+// 顶部和底部弹幕的停留时间
+const FIXED_DURATION: f64 = 4.0;
+// 弹幕中的标准字号
+const NORMAL_FONT_SIZE: f64 = 25.0;
+
+// 弹幕类型，1 到 3 为滚动，4 为底部，5 为顶部，6 为逆向滚动，7 和 8 为高级弹幕
+const MODE_BOTTOM: i32 = 4;
+const MODE_TOP: i32 = 5;
+const MODE_REVERSE: i32 = 6;
+
+#[derive(Debug, Clone, Default)]
+pub struct Danmaku {
+    // 出现时间，单位为秒
+    pub(crate) time: f64,
+    pub(crate) mode: i32,
+    pub(crate) size: i32,
+    pub(crate) color: u32,
+    // 发送时间戳
+    pub(crate) ctime: i64,
+    pub(crate) pool: i32,
+    pub(crate) mid_hash: String,
+    pub(crate) id: i64,
+    pub(crate) weight: i32,
+    pub(crate) text: String,
+}
+
+// 下载视频对应的弹幕，保存在视频旁边的同名 .xml 文件中，设置中开启时同时转换为 .ass 字幕
+pub async fn save_danmaku(cid: &str, file_path: &str) -> Result<(), String> {
+    let config = CONFIG.lock().unwrap().danmaku.clone();
+    let xml_path = get_sidecar_file_path(file_path, "xml");
+
+    let danmakus = if config.full {
+        let danmakus = get_full_danmaku(cid).await?;
+        fs::write(&xml_path, to_xml(cid, &danmakus)).map_err(|e| format!("failed to write {}: {}", xml_path, e))?;
+        danmakus
+    } else {
+        let xml = get_xml_danmaku(cid).await?;
+        fs::write(&xml_path, &xml).map_err(|e| format!("failed to write {}: {}", xml_path, e))?;
+        parse_xml(&xml)
+    };
+
+    if config.ass {
+        let ass_path = get_sidecar_file_path(file_path, "ass");
+        fs::write(&ass_path, to_ass(&danmakus, &config)).map_err(|e| format!("failed to write {}: {}", ass_path, e))?;
+    }
+
+    Ok(())
+}
+
+// list.so 返回 deflate 压缩的内容，surf 会自动解压
+pub async fn get_xml_danmaku(cid: &str) -> Result<String, String> {
+    let cookie = CONFIG.lock().unwrap().cookie.clone();
+    surf::client()
+        .get(format!("{}{}", DANMU_URL, cid))
+        .header("Cookie", cookie.as_str())
+        .header("User-Agent", Agent)
+        .await
+        .map_err(|e| format!("request danmaku of {} failed: {}", cid, e))?
+        .body_string()
+        .await
+        .map_err(|e| format!("read danmaku of {} failed: {}", cid, e))
+}
+
+async fn get_protobuf(url: &str, params: &[(&str, String)]) -> Result<Vec<u8>, String> {
+    let response = reqwest::Client::new()
+        .get(url)
+        .query(params)
+        .headers(api_headers())
+        .send()
+        .await
+        .map_err(|e| format!("request {} failed: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("request {} failed: {}", url, response.status()));
+    }
+    response.bytes().await.map(|bytes| bytes.to_vec()).map_err(|e| format!("read {} failed: {}", url, e))
+}
+
+// 通过 seg.so 分段获取全部弹幕，没有分段数量时一直获取到空的分段为止
+pub async fn get_full_danmaku(cid: &str) -> Result<Vec<Danmaku>, String> {
+    let view = get_protobuf(DANMAKU_VIEW_URL, &[("type", "1".to_string()), ("oid", cid.to_string())]).await?;
+    let total = segment_total(&view)?;
+
+    let mut danmakus = Vec::new();
+    let mut ids = HashSet::new();
+    let mut index = 1;
+    while total == 0 || index <= total {
+        let segment = get_protobuf(DANMAKU_SEG_URL, &[
+            ("type", "1".to_string()),
+            ("oid", cid.to_string()),
+            ("segment_index", index.to_string()),
+        ]).await?;
+        let elems = parse_segment(&segment)?;
+        if total == 0 && elems.is_empty() {
+            break;
+        }
+        // 相邻分段的边界处可能有重复的弹幕
+        danmakus.extend(elems.into_iter().filter(|danmaku| ids.insert(danmaku.id)));
+        index += 1;
+    }
+
+    danmakus.sort_by(|a, b| a.time.total_cmp(&b.time));
+    Ok(danmakus)
+}
+
+// protobuf 中的一个字段，定长的 fixed32 和 fixed64 也按字节处理
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or("unexpected end of protobuf")?;
+        *pos += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("varint is too long".to_string())
+}
+
+// 按顺序解析消息中的字段，返回字段编号和值
+fn proto_fields(data: &[u8]) -> Result<Vec<(u64, ProtoValue<'_>)>, String> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let key = read_varint(data, &mut pos)?;
+        let value = match key & 7 {
+            0 => ProtoValue::Varint(read_varint(data, &mut pos)?),
+            1 | 2 | 5 => {
+                let length = match key & 7 {
+                    1 => 8,
+                    5 => 4,
+                    _ => read_varint(data, &mut pos)? as usize,
+                };
+                let bytes = data.get(pos..pos + length).ok_or("unexpected end of protobuf")?;
+                pos += length;
+                ProtoValue::Bytes(bytes)
+            }
+            wire_type => return Err(format!("unsupported protobuf wire type {}", wire_type)),
+        };
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+// DmWebViewReply 中第 4 个字段是分段配置，其中第 2 个字段为分段数量
+fn segment_total(view: &[u8]) -> Result<u64, String> {
+    for (number, value) in proto_fields(view)? {
+        if let (4, ProtoValue::Bytes(config)) = (number, value) {
+            for (number, value) in proto_fields(config)? {
+                if let (2, ProtoValue::Varint(total)) = (number, value) {
+                    return Ok(total);
+                }
+            }
+        }
+    }
+    Ok(0)
+}
+
+// DmSegMobileReply 中第 1 个字段为重复的 DanmakuElem
+fn parse_segment(segment: &[u8]) -> Result<Vec<Danmaku>, String> {
+    let mut danmakus = Vec::new();
+    for (number, value) in proto_fields(segment)? {
+        let (1, ProtoValue::Bytes(elem)) = (number, value) else {
+            continue;
+        };
+
+        let mut danmaku = Danmaku::default();
+        for (number, value) in proto_fields(elem)? {
+            match (number, value) {
+                (1, ProtoValue::Varint(id)) => danmaku.id = id as i64,
+                (2, ProtoValue::Varint(progress)) => danmaku.time = progress as f64 / 1000.0,
+                (3, ProtoValue::Varint(mode)) => danmaku.mode = mode as i32,
+                (4, ProtoValue::Varint(size)) => danmaku.size = size as i32,
+                (5, ProtoValue::Varint(color)) => danmaku.color = color as u32,
+                (6, ProtoValue::Bytes(mid_hash)) => danmaku.mid_hash = String::from_utf8_lossy(mid_hash).to_string(),
+                (7, ProtoValue::Bytes(content)) => danmaku.text = String::from_utf8_lossy(content).to_string(),
+                (8, ProtoValue::Varint(ctime)) => danmaku.ctime = ctime as i64,
+                (9, ProtoValue::Varint(weight)) => danmaku.weight = weight as i32,
+                (11, ProtoValue::Varint(pool)) => danmaku.pool = pool as i32,
+                _ => {}
+            }
+        }
+        danmakus.push(danmaku);
+    }
+    Ok(danmakus)
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// p 属性依次为时间、类型、字号、颜色、发送时间、弹幕池、用户哈希、弹幕 id 和权重
+pub fn parse_xml(xml: &str) -> Vec<Danmaku> {
+    let pattern = Regex::new(r#"<d p="([^"]*)">([^<]*)</d>"#).unwrap();
+    let mut danmakus = pattern
+        .captures_iter(xml)
+        .filter_map(|caps| {
+            let p = caps[1].split(',').collect::<Vec<_>>();
+            if p.len() < 8 {
+                return None;
+            }
+            Some(Danmaku {
+                time: p[0].parse().ok()?,
+                mode: p[1].parse().ok()?,
+                size: p[2].parse().unwrap_or(NORMAL_FONT_SIZE as i32),
+                color: p[3].parse().unwrap_or(0xffffff),
+                ctime: p[4].parse().unwrap_or_default(),
+                pool: p[5].parse().unwrap_or_default(),
+                mid_hash: p[6].to_string(),
+                id: p[7].parse().unwrap_or_default(),
+                weight: p.get(8).and_then(|weight| weight.parse().ok()).unwrap_or_default(),
+                text: unescape_xml(&caps[2]),
+            })
+        })
+        .collect::<Vec<_>>();
+    danmakus.sort_by(|a, b| a.time.total_cmp(&b.time));
+    danmakus
+}
+
+// 按 list.so 的格式输出，方便其他播放器和工具读取
+pub fn to_xml(cid: &str, danmakus: &[Danmaku]) -> String {
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><i><chatserver>chat.bilibili.com</chatserver><chatid>{}</chatid><mission>0</mission><maxlimit>{}</maxlimit><state>0</state><real_name>0</real_name><source>k-v</source>",
+        cid,
+        danmakus.len(),
+    );
+    for danmaku in danmakus {
+        xml.push_str(&format!(
+            "<d p=\"{:.5},{},{},{},{},{},{},{},{}\">{}</d>",
+            danmaku.time,
+            danmaku.mode,
+            danmaku.size,
+            danmaku.color,
+            danmaku.ctime,
+            danmaku.pool,
+            danmaku.mid_hash,
+            danmaku.id,
+            danmaku.weight,
+            escape_xml(&danmaku.text),
+        ));
+    }
+    xml.push_str("</i>");
+    xml
+}
+
+fn ass_time(seconds: f64) -> String {
+    let centis = (seconds.max(0.0) * 100.0).round() as i64;
+    format!("{}:{:02}:{:02}.{:02}", centis / 360000, centis / 6000 % 60, centis / 100 % 60, centis % 100)
+}
+
+fn escape_ass(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('{', "\\{")
+        .replace('}', "\\}")
+        .replace("\r\n", "\\N")
+        .replace('\n', "\\N")
+}
+
+// 没有字体信息，按全角字符占一个字号、半角字符占一半估算宽度
+fn text_width(text: &str, font_size: f64) -> f64 {
+    text.chars().map(|c| if c.is_ascii() { font_size * 0.5 } else { font_size }).sum()
+}
+
+// 滚动弹幕所在行中最后一条弹幕的出现时间和宽度
+#[derive(Clone, Copy)]
+struct ScrollLane {
+    start: f64,
+    width: f64,
+}
+
+impl ScrollLane {
+    // 前一条已经完全进入屏幕，并且在新弹幕到达左边缘前离开屏幕时不会重叠
+    fn fits(&self, start: f64, width: f64, duration: f64) -> bool {
+        let prev_speed = (PLAY_RES_X + self.width) / duration;
+        let speed = (PLAY_RES_X + width) / duration;
+        self.start + self.width / prev_speed <= start && self.start + duration <= start + PLAY_RES_X / speed
+    }
+}
+
+// 转换为 ASS 字幕，滚动、顶部和底部弹幕分别按行排列，放不下的弹幕会被丢弃
+pub fn to_ass(danmakus: &[Danmaku], config: &DanmakuConfig) -> String {
+    let font_size = config.font_size.max(1) as f64;
+    let line_height = font_size * 1.2;
+    let lane_count = ((PLAY_RES_Y * config.density.clamp(0.0, 1.0) / line_height) as usize).max(1);
+    let duration = config.scroll_duration.max(1.0);
+    // ASS 中的透明度 00 为不透明，FF 为全透明
+    let alpha = ((1.0 - config.opacity.clamp(0.0, 1.0)) * 255.0).round() as u8;
+
+    let mut ass = format!(
+        "[Script Info]\n\
+        ScriptType: v4.00+\n\
+        PlayResX: {x}\n\
+        PlayResY: {y}\n\
+        WrapStyle: 2\n\
+        ScaledBorderAndShadow: yes\n\
+        \n\
+        [V4+ Styles]\n\
+        Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+        Style: Danmaku,Microsoft YaHei,{size},&H{alpha:02X}FFFFFF,&H{alpha:02X}FFFFFF,&H{alpha:02X}000000,&H{alpha:02X}000000,0,0,0,0,100,100,0,0,1,1,0,7,0,0,0,1\n\
+        \n\
+        [Events]\n\
+        Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+        x = PLAY_RES_X,
+        y = PLAY_RES_Y,
+        size = font_size,
+        alpha = alpha,
+    );
+
+    let mut scroll_lanes: Vec<Option<ScrollLane>> = vec![None; lane_count];
+    let mut top_lanes = vec![f64::MIN; lane_count];
+    let mut bottom_lanes = vec![f64::MIN; lane_count];
+
+    for danmaku in danmakus {
+        let size = font_size * danmaku.size.max(1) as f64 / NORMAL_FONT_SIZE;
+        let width = text_width(&danmaku.text, size);
+        let start = danmaku.time;
+
+        let (end, position) = match danmaku.mode {
+            1..=3 | MODE_REVERSE => {
+                let Some(lane) = scroll_lanes.iter().position(|lane| lane.map_or(true, |lane| lane.fits(start, width, duration))) else {
+                    continue;
+                };
+                scroll_lanes[lane] = Some(ScrollLane { start, width });
+                let y = lane as f64 * line_height;
+                let (from, to) = if danmaku.mode == MODE_REVERSE { (-width, PLAY_RES_X) } else { (PLAY_RES_X, -width) };
+                (start + duration, format!("\\move({:.0},{:.0},{:.0},{:.0})", from, y, to, y))
+            }
+            MODE_TOP => {
+                let Some(lane) = top_lanes.iter().position(|&end| end <= start) else {
+                    continue;
+                };
+                top_lanes[lane] = start + FIXED_DURATION;
+                (start + FIXED_DURATION, format!("\\an8\\pos({:.0},{:.0})", PLAY_RES_X / 2.0, lane as f64 * line_height))
+            }
+            MODE_BOTTOM => {
+                let Some(lane) = bottom_lanes.iter().position(|&end| end <= start) else {
+                    continue;
+                };
+                bottom_lanes[lane] = start + FIXED_DURATION;
+                (start + FIXED_DURATION, format!("\\an2\\pos({:.0},{:.0})", PLAY_RES_X / 2.0, PLAY_RES_Y - lane as f64 * line_height))
+            }
+            // 高级弹幕和代码弹幕无法转换
+            _ => continue,
+        };
+
+        let mut tags = position;
+        if (size - font_size).abs() >= 1.0 {
+            tags.push_str(&format!("\\fs{:.0}", size));
+        }
+        let color = danmaku.color & 0xffffff;
+        if color != 0xffffff {
+            // ASS 中颜色的顺序为 BGR
+            tags.push_str(&format!("\\c&H{:02X}{:02X}{:02X}&", color & 0xff, color >> 8 & 0xff, color >> 16));
+        }
+        ass.push_str(&format!(
+            "Dialogue: 0,{},{},Danmaku,,0,0,0,,{{{}}}{}\n",
+            ass_time(start),
+            ass_time(end),
+            tags,
+            escape_ass(&danmaku.text),
+        ));
+    }
+
+    ass
+}
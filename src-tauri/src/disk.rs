@@ -0,0 +1,90 @@
+use std::path::Path;
+use std::time::Duration;
+
+use tauri::{AppHandle, Emitter};
+
+use crate::config::CONFIG;
+use crate::download::{Download, DownloadStatus, get_download_file, running_downloads, stop_downloading};
+
+// 下载过程中检查剩余空间的间隔
+const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// 保存目录可能还没有创建，向上找到存在的目录再查询所在磁盘的剩余空间
+pub fn available_space(path: &str) -> std::io::Result<u64> {
+    let mut dir = Path::new(path);
+    while !dir.exists() {
+        match dir.parent() {
+            Some(parent) => dir = parent,
+            None => break,
+        }
+    }
+    fs2::available_space(dir)
+}
+
+// 磁盘上需要保留的最小空间（字节）
+fn min_free_space() -> u64 {
+    CONFIG.lock().unwrap().min_free_space.max(0) as u64 * 1024 * 1024
+}
+
+// 添加任务前检查剩余空间，需要合并时输出文件还需要差不多同样大的空间
+pub fn check_space_for(download: &Download) -> Result<(), String> {
+    let save_path = CONFIG.lock().unwrap().save_path.clone();
+    let available = available_space(&save_path).map_err(|e| format!("failed to query free space of {}: {}", save_path, e))?;
+
+    let mut required = (download.total_size - download.downloaded_size).max(0) as u64;
+    if download.needs_merge() {
+        required += download.total_size as u64;
+    }
+    required += min_free_space();
+
+    if available < required {
+        return Err(format!(
+            "not enough disk space under {}: {} MB required, {} MB available",
+            save_path,
+            required / 1024 / 1024,
+            available / 1024 / 1024
+        ));
+    }
+
+    Ok(())
+}
+
+// 有任务在下载时定时检查剩余空间，低于阈值时暂停所有任务
+pub async fn run_disk_monitor(app: AppHandle) {
+    let mut ticker = tokio::time::interval(DISK_CHECK_INTERVAL);
+    loop {
+        ticker.tick().await;
+
+        let ids = running_downloads().await;
+        if ids.is_empty() {
+            continue;
+        }
+
+        let save_path = CONFIG.lock().unwrap().save_path.clone();
+        let available = match available_space(&save_path) {
+            Ok(available) => available,
+            Err(e) => {
+                eprintln!("Failed to query free space of {}: {}", save_path, e);
+                continue;
+            }
+        };
+        if available >= min_free_space() {
+            continue;
+        }
+
+        let message = format!("磁盘剩余空间不足 ({} MB)，已暂停下载", available / 1024 / 1024);
+        println!("Low disk space under {}, pausing running downloads", save_path);
+        for id in ids {
+            // 排队中的任务不占用空间，开始下载后会在下一次检查时暂停
+            let downloading = get_download_file(id).await.map_or(false, |download| download.status == "downloading");
+            if downloading && stop_downloading(id).await.is_ok() {
+                app.emit("status", DownloadStatus {
+                    id,
+                    status: "paused".to_string(),
+                    attempt: 0,
+                    message: message.clone(),
+                }).unwrap();
+            }
+        }
+    }
+}
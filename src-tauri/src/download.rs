@@ -1,26 +1,34 @@
 use std::collections::HashMap;
-use std::ffi::{c_char, CString};
-use std::fs::{OpenOptions, remove_file};
+use std::fs::{metadata, OpenOptions, remove_file};
 use std::io::{BufWriter, Write};
 use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant};
 
 use lazy_static::lazy_static;
-use libloading::{Library, Symbol};
-use reqwest::Client;
+use reqwest::{Client, StatusCode};
 use reqwest::header::{HeaderMap, HeaderValue, RANGE, REFERER, USER_AGENT};
-use rusqlite::{Connection, Error, params, Result};
+use rusqlite::{Connection, params, Result, Row};
 use serde::{Deserialize, Serialize};
-use tauri::{AppHandle, Emitter, Manager};
-use tauri::path::BaseDirectory;
+use tauri::{AppHandle, Emitter};
 use tauri_plugin_shell::ShellExt;
-use tokio::sync::{mpsc, Semaphore};
+use tokio::sync::mpsc;
 use tokio::sync::Mutex;
+use tokio::task::JoinSet;
 
 use crate::Agent;
 use crate::config::CONFIG;
-use crate::path::{get_path_absolute, get_path_str, get_unique_file_path};
+use crate::disk::check_space_for;
+use crate::limiter::{GLOBAL_LIMITER, remove_task_limiter, set_task_speed_limit, task_limiter};
+use crate::scheduler::{is_download_allowed, pause_for_schedule};
+use crate::queue::{NEXT_QUEUE_POSITION, try_acquire_turn, wait_for_turn};
+use crate::merger::{MergeJob, Metadata};
+use crate::path::{get_path_absolute, get_path_str, get_stream_file_path, get_unique_file_path};
+use crate::segment::{concat_segments, create_segment_table, create_segments, delete_segments, download_segment, get_segments, remove_segment_files, save_segments, Segment, SegmentError, sync_segments_with_disk};
+use crate::stream::{pick_video_stream, VideoStream};
+use crate::subtitle::SubtitleTrack;
+use crate::{anime, danmaku, merger, subtitle, video};
 
 // 定义一个结构体来表示数据
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -38,16 +46,182 @@ pub struct Download {
     pub(crate) status: String,
     pub(crate) added_date: String,
     pub(crate) last_updated_date: String,
+    // 下载来源，链接过期后据此重新获取
+    #[serde(default)]
+    pub(crate) bvid: String,
+    #[serde(default)]
+    pub(crate) cid: String,
+    #[serde(default)]
+    pub(crate) ep_id: String,
+    // 选择的视频流在 dash 中的序号，没有记录 qn 的旧任务刷新链接时使用
+    #[serde(default)]
+    pub(crate) quality: i32,
+    // 视频流的清晰度代码和编码，都为 0 时添加任务按设置自动选择
+    #[serde(default)]
+    pub(crate) qn: i64,
+    #[serde(default)]
+    pub(crate) codecid: i64,
+    // 备用 CDN 链接，主链接出错或过慢时依次切换
+    #[serde(default)]
+    pub(crate) video_backup_urls: Vec<String>,
+    #[serde(default)]
+    pub(crate) audio_backup_urls: Vec<String>,
+    // 单个任务的下载速度上限（KB/s），为空时只受全局限速
+    #[serde(default)]
+    pub(crate) speed_limit: Option<i64>,
+    // 排队顺序，优先级高的先下载，同一优先级按队列位置
+    #[serde(default)]
+    pub(crate) queue_position: i64,
+    #[serde(default)]
+    pub(crate) priority: i32,
+    // 任务的开始时间，格式为 %Y-%m-%d %H:%M:%S，为空时立即开始
+    #[serde(default)]
+    pub(crate) start_at: String,
+    // 输出的封装格式，mp4 或 mkv，为空时使用设置中的默认值，仅下载音频时为 m4a 或 flac
+    #[serde(default)]
+    pub(crate) container: String,
+    // 合并时写入输出文件的标题、作者、日期、简介和封面
+    #[serde(default)]
+    pub(crate) metadata: Metadata,
+    // 要下载的 CC 字幕的语言代码，下载完成前根据 bvid 和 cid 获取字幕链接
+    #[serde(default)]
+    pub(crate) subtitles: Vec<String>,
 }
 
-// 定义下载进度用于发布事件
+// 定义下载状态变化用于发布事件，attempt 为重试的次数
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DownloadStatus {
+    pub(crate) id: i32,
+    pub(crate) status: String,
+    pub(crate) attempt: u32,
+    pub(crate) message: String,
+}
+
+// 定义下载进度用于发布事件，chunk_length 为已下载的总字节数，下载失败时为 -1
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DownloadProgress {
     pub(crate) id: i32,
     pub(crate) chunk_length: i64,
+    // 平滑后的瞬时速度和本次开始以来的平均速度（字节/秒）
+    pub(crate) speed: i64,
+    pub(crate) average_speed: i64,
+    // 预计剩余的秒数，速度未知时为空
+    pub(crate) eta: Option<i64>,
+    // 当前阶段：video、audio、merging 或 completed
+    pub(crate) phase: String,
+    pub(crate) video_downloaded: i64,
+    pub(crate) audio_downloaded: i64,
+}
+
+impl DownloadProgress {
+    fn new(download: &Download, phase: &str, meter: &SpeedMeter) -> Self {
+        let downloaded = download.downloaded_size;
+        let speed = meter.speed();
+        let average_speed = meter.average_speed(downloaded);
+        // 刚开始时瞬时速度还不稳定，用平均速度估计
+        let estimate = if speed > 0 { speed } else { average_speed };
+        let eta = if estimate > 0 {
+            Some((download.total_size - downloaded).max(0) / estimate)
+        } else {
+            None
+        };
+
+        DownloadProgress {
+            id: download.id,
+            chunk_length: downloaded,
+            speed,
+            average_speed,
+            eta,
+            phase: phase.to_string(),
+            // 音频的进度排在视频之后
+            video_downloaded: downloaded.min(download.video_size),
+            audio_downloaded: (downloaded - download.video_size).clamp(0, download.audio_size),
+        }
+    }
+
+    fn failed(download: &Download) -> Self {
+        DownloadProgress {
+            id: download.id,
+            chunk_length: -1,
+            speed: 0,
+            average_speed: 0,
+            eta: None,
+            phase: String::new(),
+            video_downloaded: download.downloaded_size.min(download.video_size),
+            audio_downloaded: (download.downloaded_size - download.video_size).clamp(0, download.audio_size),
+        }
+    }
+}
+
+// 指数平滑的速度计，只统计本次开始以来下载的字节，断点续传的部分不计入平均速度
+struct SpeedMeter {
+    started: Instant,
+    start_bytes: i64,
+    last: Instant,
+    last_bytes: i64,
+    speed: Option<f64>,
+}
+
+// 平滑系数，越大越接近瞬时速度
+const SPEED_SMOOTHING: f64 = 0.3;
+
+impl SpeedMeter {
+    fn new(bytes: i64) -> Self {
+        let now = Instant::now();
+        SpeedMeter {
+            started: now,
+            start_bytes: bytes,
+            last: now,
+            last_bytes: bytes,
+            speed: None,
+        }
+    }
+
+    fn update(&mut self, bytes: i64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        if elapsed <= 0.0 {
+            return;
+        }
+        let current = (bytes - self.last_bytes).max(0) as f64 / elapsed;
+        self.speed = Some(match self.speed {
+            Some(speed) => SPEED_SMOOTHING * current + (1.0 - SPEED_SMOOTHING) * speed,
+            None => current,
+        });
+        self.last = now;
+        self.last_bytes = bytes;
+    }
+
+    // 切换 CDN 或刷新链接后以磁盘上的进度为准，不计入速度
+    fn reset(&mut self, bytes: i64) {
+        self.start_bytes -= self.last_bytes - bytes;
+        self.last = Instant::now();
+        self.last_bytes = bytes;
+    }
+
+    fn speed(&self) -> i64 {
+        self.speed.unwrap_or(0.0) as i64
+    }
+
+    fn average_speed(&self, bytes: i64) -> i64 {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        if elapsed < 1.0 {
+            return 0;
+        }
+        ((bytes - self.start_bytes).max(0) as f64 / elapsed) as i64
+    }
 }
 
 impl Download {
+    // 音视频分开下载、需要转换封装格式、写入标签或字幕时，下载到临时文件后再合并
+    pub fn needs_merge(&self) -> bool {
+        (self.video_size != 0 && self.audio_size != 0) || self.container != "mp4" || !self.metadata.is_empty() || !self.subtitles.is_empty()
+    }
+
+    pub fn is_audio_only(&self) -> bool {
+        self.container == "m4a" || self.container == "flac"
+    }
+
     fn to_params(&self) -> (String, String, String, String, String, i64, i64, i64, i64, String, String) {
         (
             self.video_url.clone(),
@@ -65,26 +239,91 @@ impl Download {
     }
 }
 
+// 查询下载记录时的列，顺序与 read_download 对应
+const DOWNLOAD_COLUMNS: &str = "id, video_url, audio_url, file_name, file_path, referer, video_size, audio_size, total_size, downloaded_size, status, added_date, last_updated_date, bvid, cid, ep_id, quality, video_backup_urls, audio_backup_urls, speed_limit, queue_position, priority, start_at, container, metadata, qn, codecid, subtitles";
+
+fn read_download(row: &Row) -> Result<Download> {
+    Ok(Download {
+        id: row.get(0)?,
+        video_url: row.get(1)?,
+        audio_url: row.get(2)?,
+        file_name: row.get(3)?,
+        file_path: row.get(4)?,
+        referer: row.get(5)?,
+        video_size: row.get(6)?,
+        audio_size: row.get(7)?,
+        total_size: row.get(8)?,
+        downloaded_size: row.get(9)?,
+        status: row.get(10)?,
+        added_date: row.get(11)?,
+        last_updated_date: row.get(12)?,
+        bvid: row.get(13)?,
+        cid: row.get(14)?,
+        ep_id: row.get(15)?,
+        quality: row.get(16)?,
+        video_backup_urls: serde_json::from_str(&row.get::<_, String>(17)?).unwrap_or_default(),
+        audio_backup_urls: serde_json::from_str(&row.get::<_, String>(18)?).unwrap_or_default(),
+        speed_limit: row.get(19)?,
+        queue_position: row.get(20)?,
+        priority: row.get(21)?,
+        start_at: row.get(22)?,
+        container: row.get(23)?,
+        metadata: serde_json::from_str(&row.get::<_, String>(24)?).unwrap_or_default(),
+        qn: row.get(25)?,
+        codecid: row.get(26)?,
+        subtitles: serde_json::from_str(&row.get::<_, String>(27)?).unwrap_or_default(),
+    })
+}
+
+// 下载速度连续低于最低速度的秒数上限
+const SLOW_TICKS_LIMIT: i32 = 10;
+
 lazy_static! {
     static ref TASK_MAP: Arc<Mutex<HashMap<i32, mpsc::Sender<()>>>> =
         Arc::new(Mutex::new(HashMap::new()));
-    static ref CONN: Mutex<Connection> = Mutex::new(create_table("downloads.db").unwrap());
-    static ref SEMAPHORE: Arc<Semaphore> = Arc::new(Semaphore::new(3));
+    pub(crate) static ref CONN: Mutex<Connection> = Mutex::new(create_table("downloads.db").unwrap());
 }
 
-pub async fn add_download_file(app: AppHandle, mut download: Download) -> Result<()> {
+pub async fn add_download_file(app: AppHandle, mut download: Download) -> Result<(), String> {
+    if download.is_audio_only() {
+        select_audio_stream(&mut download).await?;
+    }
+
     {
-        let mut config = CONFIG.lock().unwrap();
-        download.file_path = get_path_absolute(&config.save_path, &[(format!("{}{}", download.file_name, ".mp4")).as_str()]);
+        let config = CONFIG.lock().unwrap();
+        if download.container.is_empty() {
+            download.container = config.container.clone();
+        }
+        if !["mp4", "mkv", "m4a", "flac"].contains(&download.container.as_str()) {
+            return Err(format!("unsupported container: {}", download.container));
+        }
+        // 未开启写入标签时不带标签，单独的 mp4 流可以直接保存而不需要合并
+        if !config.embed_metadata {
+            download.metadata = Metadata::default();
+        }
+        if download.is_audio_only() {
+            download.video_url.clear();
+            download.video_backup_urls.clear();
+            download.video_size = 0;
+            download.subtitles.clear();
+            if download.audio_url.is_empty() {
+                return Err("audio only download has no audio stream".to_string());
+            }
+        }
+        download.file_path = get_path_absolute(&config.save_path, &[(format!("{}.{}", download.file_name, download.container)).as_str()]);
         download.file_path = get_unique_file_path(&download.file_path);
     }
 
+    if !download.is_audio_only() && download.video_url.is_empty() {
+        select_video_stream(&mut download).await?;
+    }
+
     if !download.video_url.is_empty() && download.video_size == 0 {
         match get_file_size(&download.video_url, &download.referer).await {
             Ok(size) => download.video_size = size,
             Err(err) => {
                 println!("{}", err);
-                return Err(Error::SqliteSingleThreadedMode);
+                return Err(err);
             }
         }
     }
@@ -93,52 +332,49 @@ pub async fn add_download_file(app: AppHandle, mut download: Download) -> Result
             Ok(size) => download.audio_size = size,
             Err(err) => {
                 println!("{}", err);
-                return Err(Error::SqliteSingleThreadedMode);
+                return Err(err);
             }
         }
     }
     download.total_size = download.audio_size + download.video_size;
+    check_space_for(&download)?;
+
+    // 不在允许的时间段内或未到开始时间，等待调度器启动
+    if !is_download_allowed(&download) {
+        download.status = "scheduled".to_string();
+    }
 
     let id;
     // 插入数据
     {
         let conn = &*CONN.lock().await;
         if let Err(e) = conn.execute(
-            "INSERT INTO downloads (video_url, audio_url, file_name, file_path, referer, video_size, audio_size, total_size, downloaded_size, status, added_date, last_updated_date)
-     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
-            params![download.video_url, download.audio_url, download.file_name, download.file_path, download.referer, download.video_size, download.audio_size, download.total_size, download.downloaded_size, download.status, download.added_date, download.last_updated_date],
+            &format!("INSERT INTO downloads (video_url, audio_url, file_name, file_path, referer, video_size, audio_size, total_size, downloaded_size, status, added_date, last_updated_date, bvid, cid, ep_id, quality, video_backup_urls, audio_backup_urls, speed_limit, priority, start_at, container, metadata, qn, codecid, subtitles, queue_position)
+     VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26, {})", NEXT_QUEUE_POSITION),
+            params![download.video_url, download.audio_url, download.file_name, download.file_path, download.referer, download.video_size, download.audio_size, download.total_size, download.downloaded_size, download.status, download.added_date, download.last_updated_date, download.bvid, download.cid, download.ep_id, download.quality, serde_json::to_string(&download.video_backup_urls).unwrap(), serde_json::to_string(&download.audio_backup_urls).unwrap(), download.speed_limit, download.priority, download.start_at, download.container, serde_json::to_string(&download.metadata).unwrap(), download.qn, download.codecid, serde_json::to_string(&download.subtitles).unwrap()],
         ) {
             eprintln!("Error inserting data: {}", e);
         }
         id = conn.last_insert_rowid() as i32;
     }
 
-    tokio::spawn(start_downloading(app, id));
+    if download.status != "scheduled" {
+        tokio::spawn(start_downloading(app, id));
+    }
 
     Ok(())
 }
 
+// 已经开始的任务，包括正在排队的
+pub async fn running_downloads() -> Vec<i32> {
+    TASK_MAP.lock().await.keys().cloned().collect()
+}
+
 pub async fn get_all_downloading_files() -> Result<Vec<Download>> {
     // 查询数据
     let conn = &*CONN.lock().await;
-    let mut stmt = conn.prepare("SELECT id, video_url, audio_url, file_name, file_path, referer, video_size, audio_size, total_size, downloaded_size, status, added_date, last_updated_date FROM downloads WHERE status == 'downloading' OR status == 'paused'")?;
-    let download_iter = stmt.query_map([], |row| {
-        Ok(Download {
-            id: row.get(0)?,
-            video_url: row.get(1)?,
-            audio_url: row.get(2)?,
-            file_name: row.get(3)?,
-            file_path: row.get(4)?,
-            referer: row.get(5)?,
-            video_size: row.get(6)?,
-            audio_size: row.get(7)?,
-            total_size: row.get(8)?,
-            downloaded_size: row.get(9)?,
-            status: row.get(10)?,
-            added_date: row.get(11)?,
-            last_updated_date: row.get(12)?,
-        })
-    })?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM downloads WHERE status == 'downloading' OR status == 'paused' OR status == 'queued' OR status == 'scheduled' ORDER BY priority DESC, queue_position ASC", DOWNLOAD_COLUMNS))?;
+    let download_iter = stmt.query_map([], read_download)?;
 
     let mut downloads = Vec::new();
     for download in download_iter {
@@ -151,24 +387,8 @@ pub async fn get_all_downloading_files() -> Result<Vec<Download>> {
 pub async fn get_all_downloaded_files() -> Result<Vec<Download>> {
     // 查询数据
     let conn = &*CONN.lock().await;
-    let mut stmt = conn.prepare("SELECT id, video_url, audio_url, file_name, file_path, referer, video_size, audio_size, total_size, downloaded_size, status, added_date, last_updated_date FROM downloads WHERE status == 'completed'")?;
-    let download_iter = stmt.query_map([], |row| {
-        Ok(Download {
-            id: row.get(0)?,
-            video_url: row.get(1)?,
-            audio_url: row.get(2)?,
-            file_name: row.get(3)?,
-            file_path: row.get(4)?,
-            referer: row.get(5)?,
-            video_size: row.get(6)?,
-            audio_size: row.get(7)?,
-            total_size: row.get(8)?,
-            downloaded_size: row.get(9)?,
-            status: row.get(10)?,
-            added_date: row.get(11)?,
-            last_updated_date: row.get(12)?,
-        })
-    })?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM downloads WHERE status == 'completed'", DOWNLOAD_COLUMNS))?;
+    let download_iter = stmt.query_map([], read_download)?;
 
     let mut downloads = Vec::new();
     for download in download_iter {
@@ -182,24 +402,21 @@ pub async fn search_downloads(text: String) -> Result<Vec<Download>> {
     let search_pattern = format!("%{}%", text);
     // 查询数据
     let conn = &*CONN.lock().await;
-    let mut stmt = conn.prepare("SELECT id, video_url, audio_url, file_name, file_path, referer, video_size, audio_size, total_size, downloaded_size, status, added_date, last_updated_date FROM downloads WHERE file_name like ?1")?;
-    let download_iter = stmt.query_map([search_pattern], |row| {
-        Ok(Download {
-            id: row.get(0)?,
-            video_url: row.get(1)?,
-            audio_url: row.get(2)?,
-            file_name: row.get(3)?,
-            file_path: row.get(4)?,
-            referer: row.get(5)?,
-            video_size: row.get(6)?,
-            audio_size: row.get(7)?,
-            total_size: row.get(8)?,
-            downloaded_size: row.get(9)?,
-            status: row.get(10)?,
-            added_date: row.get(11)?,
-            last_updated_date: row.get(12)?,
-        })
-    })?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM downloads WHERE file_name like ?1", DOWNLOAD_COLUMNS))?;
+    let download_iter = stmt.query_map([search_pattern], read_download)?;
+
+    let mut downloads = Vec::new();
+    for download in download_iter {
+        downloads.push(download?);
+    }
+
+    Ok(downloads)
+}
+
+pub async fn get_downloads_with_status(status: &str) -> Result<Vec<Download>> {
+    let conn = &*CONN.lock().await;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM downloads WHERE status == ?1 ORDER BY priority DESC, queue_position ASC", DOWNLOAD_COLUMNS))?;
+    let download_iter = stmt.query_map([status], read_download)?;
 
     let mut downloads = Vec::new();
     for download in download_iter {
@@ -213,6 +430,11 @@ pub async fn delete_download_file(id: i32) -> Result<()> {
     // 先暂停下下载，如果正在下载的话
     let _ = stop_downloading(id).await;
 
+    // 删除分段进度
+    if let Err(e) = delete_segments(id, None).await {
+        eprintln!("Error deleting segments: {}", e);
+    }
+
     // 删除数据
     let conn = &*CONN.lock().await;
     if let Err(e) = conn.execute("DELETE FROM downloads WHERE id = ?1", params![id]) {
@@ -225,24 +447,8 @@ pub async fn delete_download_file(id: i32) -> Result<()> {
 pub async fn get_download_file(id: i32) -> Result<(Download)> {
     // 查询数据
     let conn = &*CONN.lock().await;
-    let mut stmt = conn.prepare("SELECT id, video_url, audio_url, file_name, file_path, referer, video_size, audio_size, total_size, downloaded_size, status, added_date, last_updated_date FROM downloads WHERE id = ?1")?;
-    let download = stmt.query_row([id], |row| {
-        Ok(Download {
-            id: row.get(0)?,
-            video_url: row.get(1)?,
-            audio_url: row.get(2)?,
-            file_name: row.get(3)?,
-            file_path: row.get(4)?,
-            referer: row.get(5)?,
-            video_size: row.get(6)?,
-            audio_size: row.get(7)?,
-            total_size: row.get(8)?,
-            downloaded_size: row.get(9)?,
-            status: row.get(10)?,
-            added_date: row.get(11)?,
-            last_updated_date: row.get(12)?,
-        })
-    })?;
+    let mut stmt = conn.prepare(&format!("SELECT {} FROM downloads WHERE id = ?1", DOWNLOAD_COLUMNS))?;
+    let download = stmt.query_row([id], read_download)?;
 
     Ok(download)
 }
@@ -250,8 +456,8 @@ pub async fn get_download_file(id: i32) -> Result<(Download)> {
 pub async fn update_download_file(download: &Download) -> Result<()> {
     let conn = &*CONN.lock().await;
     if let Err(e) = conn.execute(
-        "UPDATE downloads SET video_size = ?1, audio_size = ?2, total_size = ?3, downloaded_size = ?4, status = ?5, last_updated_date = ?6 WHERE id = ?7",
-        params![download.video_size, download.audio_size, download.total_size, download.downloaded_size, download.status, download.last_updated_date, download.id],
+        "UPDATE downloads SET video_url = ?1, audio_url = ?2, video_backup_urls = ?3, audio_backup_urls = ?4, video_size = ?5, audio_size = ?6, total_size = ?7, downloaded_size = ?8, status = ?9, last_updated_date = ?10 WHERE id = ?11",
+        params![download.video_url, download.audio_url, serde_json::to_string(&download.video_backup_urls).unwrap(), serde_json::to_string(&download.audio_backup_urls).unwrap(), download.video_size, download.audio_size, download.total_size, download.downloaded_size, download.status, download.last_updated_date, download.id],
     ) {
         eprintln!("Error inserting data: {}", e);
     }
@@ -259,6 +465,39 @@ pub async fn update_download_file(download: &Download) -> Result<()> {
     Ok(())
 }
 
+pub async fn update_download_status(id: i32, status: &str) -> Result<()> {
+    let conn = &*CONN.lock().await;
+    conn.execute("UPDATE downloads SET status = ?1 WHERE id = ?2", params![status, id])?;
+
+    Ok(())
+}
+
+// 修改任务的开始时间，未到开始时间的任务交给调度器启动
+pub async fn update_start_at(app: &AppHandle, id: i32, start_at: String) -> Result<()> {
+    {
+        let conn = &*CONN.lock().await;
+        conn.execute("UPDATE downloads SET start_at = ?1 WHERE id = ?2", params![start_at, id])?;
+    }
+
+    let download = get_download_file(id).await?;
+    if !is_download_allowed(&download) && (download.status == "downloading" || download.status == "queued") {
+        pause_for_schedule(app, id).await;
+    }
+
+    Ok(())
+}
+
+// 修改单个任务的限速，正在下载的任务立即生效
+pub async fn update_speed_limit(id: i32, limit: Option<i64>) -> Result<()> {
+    {
+        let conn = &*CONN.lock().await;
+        conn.execute("UPDATE downloads SET speed_limit = ?1 WHERE id = ?2", params![limit, id])?;
+    }
+    set_task_speed_limit(id, limit);
+
+    Ok(())
+}
+
 pub async fn start_downloading(app: AppHandle, id: i32) -> Result<(), String> {
     let (tx, mut rx) = mpsc::channel(1);
     {
@@ -267,17 +506,38 @@ pub async fn start_downloading(app: AppHandle, id: i32) -> Result<(), String> {
         if map.contains_key(&id) {
             return Err("task already existed".to_string());
         }
-        map.insert(id, tx);
+        map.insert(id, tx.clone());
     }
 
-    let mut download = get_download_file(id).await.unwrap();
-    if download.status == "paused" {
-        download.status = "downloading".to_string();
-        update_download_file(&download).await.unwrap();
-    }
+    let mut download = match get_download_file(id).await {
+        Ok(download) => download,
+        Err(e) => {
+            remove_task(id, &tx).await;
+            return Err(format!("failed to load download {}: {}", id, e));
+        }
+    };
 
     // 启动下载任务
+    let mut attempt = 0;
     loop {
+        // 没有空闲的下载名额或前面还有任务时进入排队状态
+        let permit = match try_acquire_turn(id).await {
+            Some(permit) => permit,
+            None => {
+                set_download_status(&app, &mut download, "queued", attempt).await;
+                tokio::select! {
+                    permit = wait_for_turn(id) => permit,
+                    Some(_) = rx.recv() => {
+                        println!("Download interrupted");
+                        break;
+                    }
+                }
+            }
+        };
+        if download.status != "downloading" {
+            set_download_status(&app, &mut download, "downloading", attempt).await;
+        }
+
         tokio::select! {
             // 下载任务
             result = download_file(&app, &mut download) => {
@@ -287,13 +547,46 @@ pub async fn start_downloading(app: AppHandle, id: i32) -> Result<(), String> {
                         break;
                     }
                     Err(e) => {
+                        let policy = CONFIG.lock().unwrap().retry.clone();
+                        attempt += 1;
+                        if attempt < policy.max_attempts && e.is_retryable(&policy) {
+                            let delay = policy.backoff(attempt);
+                            eprintln!("Download failed with error: {}, retry {}/{} in {:?}", e, attempt, policy.max_attempts - 1, delay);
+                            app.emit("status", DownloadStatus {
+                                id: download.id,
+                                status: "retrying".to_string(),
+                                attempt,
+                                message: e.to_string(),
+                            }).unwrap();
+
+                            // 等待期间让出下载名额，同样响应取消信号
+                            drop(permit);
+                            tokio::select! {
+                                _ = tokio::time::sleep(delay) => {}
+                                Some(_) = rx.recv() => {
+                                    println!("Download interrupted");
+                                    break;
+                                }
+                            }
+
+                            // 从数据库中保存的进度继续
+                            download = match get_download_file(id).await {
+                                Ok(download) => download,
+                                Err(e) => {
+                                    eprintln!("Failed to reload download {}: {}", id, e);
+                                    break;
+                                }
+                            };
+                            download.status = "retrying".to_string();
+                            continue;
+                        }
+
                         eprintln!("Download failed with error: {}", e);
-                        app.emit("progress", DownloadProgress {
-                           id: download.id,
-                           chunk_length: -1,
-                        }).unwrap();
+                        app.emit("progress", DownloadProgress::failed(&download)).unwrap();
                         download.status = "failed".to_string();
-                        update_download_file(&download).await.unwrap();
+                        if let Err(e) = update_download_file(&download).await {
+                            eprintln!("Failed to save status of download {}: {}", id, e);
+                        }
                         break;
                     }
                 }
@@ -305,175 +598,384 @@ pub async fn start_downloading(app: AppHandle, id: i32) -> Result<(), String> {
             }
         }
     }
+    remove_task(id, &tx).await;
+    remove_task_limiter(id);
 
     Ok(())
 }
 
-pub async fn download_file(app: &AppHandle, mut download: &mut Download) -> Result<(), String> {
-    let permit = SEMAPHORE.acquire().await.unwrap();
+// 任务结束时从 TASK_MAP 中移除，已被 stop_downloading 移除或者被同 id 的新任务替换时不处理
+async fn remove_task(id: i32, tx: &mpsc::Sender<()>) {
+    let mut map = TASK_MAP.lock().await;
+    if map.get(&id).is_some_and(|stored| stored.same_channel(tx)) {
+        map.remove(&id);
+    }
+}
+
+// 修改任务状态，保存到数据库并通知前端
+async fn set_download_status(app: &AppHandle, download: &mut Download, status: &str, attempt: u32) {
+    download.status = status.to_string();
+    if let Err(e) = update_download_file(download).await {
+        eprintln!("Failed to save status of download {}: {}", download.id, e);
+    }
+    app.emit("status", DownloadStatus {
+        id: download.id,
+        status: status.to_string(),
+        attempt,
+        message: String::new(),
+    }).unwrap();
+}
+
+pub async fn download_file(app: &AppHandle, mut download: &mut Download) -> Result<(), SegmentError> {
     {
         let mut map = TASK_MAP.lock().await;
         if !map.contains_key(&download.id) {
-            return Err(format!("downloading error, {:?} didn't exist", download));
+            return Err(SegmentError::Other(format!("downloading error, {:?} didn't exist", download)));
         }
     }
 
-    let client = Client::new();
-    // 构造文件路径
+    // 构造文件路径，不需要合并时直接下载到输出文件
+    let merge = download.needs_merge();
     let mut video_file = download.file_path.clone();
     let mut audio_file = download.file_path.clone();
-    if download.video_size != 0 && download.audio_size != 0 {
-        video_file = download.file_path.replace(".mp4", "_video.mp4");
-        audio_file = download.file_path.replace(".mp4", "_audio.mp4");
-    }
-
-    // 断点续传下载文件
-    if download.downloaded_size < download.video_size && download.video_size != 0 {
-        let mut headers1 = HeaderMap::new();
-        headers1.insert(
-            RANGE,
-            format!(
-                "bytes={}-{}",
-                &download.downloaded_size, &download.video_size
-            )
-                .parse()
-                .unwrap(),
-        );
-        headers1.insert(USER_AGENT, HeaderValue::from_static(Agent));
-        headers1.insert(REFERER, HeaderValue::from_str(&download.referer).unwrap());
-
-        let mut res = client
-            .get(&download.video_url)
-            .headers(headers1)
-            .send()
-            .await
-            .unwrap();
-
-        if !res.status().is_success() {
-            return Err(format!("request video url failed, response: {:?}", res));
-        }
-
-        let mut downloaded_file_size = download.downloaded_size;
-
-        let path = Path::new(&video_file);
-
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(path)
-            .unwrap();
-        let mut writer = BufWriter::new(&file);
-
-        let mut last_update_time = tokio::time::Instant::now();
-        let update_interval = Duration::from_secs(1); // 每 2 秒更新一次
-        while let Some(chunk) = res.chunk().await.unwrap() {
-            downloaded_file_size += chunk.len() as i64;
-            writer.write_all(&chunk).unwrap();
-            writer.flush().unwrap();
-
-            // 计算进度
-            let percent_completed = (downloaded_file_size * 100 / download.total_size) as u32;
-            println!("Downloaded {} bytes", downloaded_file_size);
-            println!("Download progress: {}%", percent_completed);
-
-            download.downloaded_size = downloaded_file_size;
-
-            // 检查是否需要更新
-            if tokio::time::Instant::now().duration_since(last_update_time) >= update_interval {
-                let download_clone = download.clone();
-                app.emit("progress", DownloadProgress {
-                    id: download.id,
-                    chunk_length: download.downloaded_size,
-                }).unwrap();
-                tokio::spawn(async move {
-                    update_download_file(&download_clone).await.unwrap();
-                });
-                last_update_time = tokio::time::Instant::now();
-            }
-        }
+    if merge {
+        video_file = get_stream_file_path(&download.file_path, "video");
+        audio_file = get_stream_file_path(&download.file_path, "audio");
     }
 
-    if download.total_size - download.downloaded_size <= download.audio_size && download.audio_size != 0 {
-        let mut headers1 = HeaderMap::new();
-        headers1.insert(
-            RANGE,
-            format!(
-                "bytes={}-{}",
-                &download.downloaded_size - download.video_size, &download.audio_size
-            )
-                .parse()
-                .unwrap(),
-        );
-        headers1.insert(USER_AGENT, HeaderValue::from_static(Agent));
-        headers1.insert(REFERER, HeaderValue::from_str(&download.referer).unwrap());
-
-        let mut res = client
-            .get(&download.audio_url)
-            .headers(headers1)
-            .send()
-            .await
-            .unwrap();
-
-        if !res.status().is_success() {
-            return Err(format!("request video url failed, response: {:?}", res));
-        }
-
-        let mut downloaded_file_size = download.downloaded_size;
-
-        let path = Path::new(&audio_file);
-
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .append(true)
-            .open(path)
-            .unwrap();
-        let mut writer = BufWriter::new(&file);
-
-        let mut last_update_time = tokio::time::Instant::now();
-        let update_interval = Duration::from_secs(1); // 每 2 秒更新一次
-        while let Some(chunk) = res.chunk().await.unwrap() {
-            downloaded_file_size += chunk.len() as i64;
-            writer.write_all(&chunk).unwrap();
-            writer.flush().unwrap();
-
-            // 计算进度
-            let percent_completed = (downloaded_file_size * 100 / download.total_size) as u32;
-            println!("Downloaded {} bytes", downloaded_file_size);
-            println!("Download progress: {}%", percent_completed);
-
-            download.downloaded_size = downloaded_file_size;
-
-            // 检查是否需要更新
-            if tokio::time::Instant::now().duration_since(last_update_time) >= update_interval {
-                let download_clone = download.clone();
-                app.emit("progress", DownloadProgress {
-                    id: download.id,
-                    chunk_length: download.downloaded_size,
-                }).unwrap();
-                tokio::spawn(async move {
-                    update_download_file(&download_clone).await.unwrap();
-                });
-                last_update_time = tokio::time::Instant::now();
-            }
+    // 分段下载视频和音频，音频的进度排在视频之后
+    let mut meter = SpeedMeter::new(download.downloaded_size);
+    if download.video_size != 0 {
+        let size = download.video_size;
+        download_stream(app, download, "video", &video_file, size, 0, &mut meter).await?;
+    }
+
+    if download.audio_size != 0 {
+        let size = download.audio_size;
+        let offset = download.video_size;
+        download_stream(app, download, "audio", &audio_file, size, offset, &mut meter).await?;
+    }
+
+    // 字幕在合并前保存，需要时作为软字幕写入，获取失败不影响视频本身
+    let mut subtitles = Vec::new();
+    if !download.subtitles.is_empty() && !download.bvid.is_empty() && !download.cid.is_empty() {
+        match subtitle::save_subtitles(&download.bvid, &download.cid, &download.subtitles, &download.file_path).await {
+            Ok(tracks) => subtitles = tracks,
+            Err(e) => eprintln!("Failed to save subtitles of {}: {}", download.file_name, e),
         }
     }
 
-    if download.video_size != 0 && download.audio_size != 0 {
-        match merge_file(app, download).await {
+    if merge {
+        let mut inputs = Vec::new();
+        if download.video_size != 0 {
+            inputs.push(video_file);
+        }
+        if download.audio_size != 0 {
+            inputs.push(audio_file);
+        }
+        app.emit("progress", DownloadProgress::new(download, "merging", &meter)).unwrap();
+        match merge_file(download, inputs, subtitles).await {
             Ok(_) => {}
-            Err(err) => { return Err(err) }
+            Err(err) => { return Err(SegmentError::Other(err)) }
+        }
+    }
+
+    // 弹幕保存失败不影响视频本身
+    let save_danmaku = CONFIG.lock().unwrap().danmaku.enabled;
+    if save_danmaku && !download.cid.is_empty() && !download.is_audio_only() {
+        if let Err(e) = danmaku::save_danmaku(&download.cid, &download.file_path).await {
+            eprintln!("Failed to save danmaku of {}: {}", download.file_name, e);
         }
     }
 
     download.downloaded_size = download.total_size;
     download.status = "completed".to_string();
     update_download_file(download).await.unwrap();
-    app.emit("progress", DownloadProgress {
-        id: download.id,
-        chunk_length: download.total_size,
-    }).unwrap();
+    app.emit("progress", DownloadProgress::new(download, "completed", &meter)).unwrap();
+
+    Ok(())
+}
+
+// 多连接下载单个音视频流，每个分段并行下载，完成后按顺序拼接
+async fn download_stream(app: &AppHandle, download: &mut Download, stream: &str, file: &str, size: i64, offset: i64, meter: &mut SpeedMeter) -> Result<(), SegmentError> {
+    // 已经拼接完成的流直接跳过
+    let mut segments = get_segments(download.id, stream).await.map_err(|e| SegmentError::Other(e.to_string()))?;
+    if segments.is_empty() && metadata(file).map(|m| m.len() as i64 == size).unwrap_or(false) {
+        return Ok(());
+    }
+
+    if segments.is_empty() {
+        let count = CONFIG.lock().unwrap().segment_count;
+        segments = create_segments(download.id, stream, file, size, count);
+        save_segments(&segments).await.map_err(|e| SegmentError::Other(e.to_string()))?;
+    }
+    sync_segments_with_disk(&mut segments, file);
+    meter.reset(offset + segments.iter().map(|segment| segment.downloaded).sum::<i64>());
+
+    // 链接过期或失效时刷新一次，其余错误依次切换备用 CDN，都从已下载的位置继续
+    let mut refreshed = false;
+    let mut mirror = 0;
+    loop {
+        if !refreshed && is_url_expired(stream_url(download, stream)) {
+            refresh_download_urls(download).await.map_err(SegmentError::Other)?;
+            refreshed = true;
+        }
+
+        let mirrors = stream_mirrors(download, stream);
+        let url = mirrors[mirror.min(mirrors.len() - 1)].clone();
+        match run_segments(app, download, &mut segments, &url, file, stream, meter).await {
+            Ok(_) => break,
+            Err(SegmentError::Status(status)) if !refreshed && (status == StatusCode::FORBIDDEN || status == StatusCode::NOT_FOUND) => {
+                println!("Stream url of download {} is no longer valid, refreshing", download.id);
+                refresh_download_urls(download).await.map_err(SegmentError::Other)?;
+                refreshed = true;
+                mirror = 0;
+            }
+            Err(err) if mirror + 1 < mirrors.len() => {
+                mirror += 1;
+                println!("Mirror of download {} failed: {}, switching to {}", download.id, err, mirrors[mirror]);
+            }
+            Err(err) => return Err(err),
+        }
+        sync_segments_with_disk(&mut segments, file);
+        meter.reset(offset + segments.iter().map(|segment| segment.downloaded).sum::<i64>());
+    }
+
+    download.downloaded_size = offset + size;
+    // 先删除分段记录再删除分段文件，中途退出时记录不会指向已删除的文件
+    concat_segments(file, &segments).map_err(SegmentError::Other)?;
+    delete_segments(download.id, Some(stream)).await.map_err(|e| SegmentError::Other(e.to_string()))?;
+    remove_segment_files(file, &segments);
+    update_download_file(download).await.map_err(|e| SegmentError::Other(e.to_string()))?;
+
+    Ok(())
+}
+
+// 并行下载所有未完成的分段，并定时发布进度
+async fn run_segments(app: &AppHandle, download: &mut Download, segments: &mut [Segment], url: &str, file: &str, stream: &str, meter: &mut SpeedMeter) -> Result<(), SegmentError> {
+    // 音频的进度排在视频之后
+    let offset = if stream == "audio" { download.video_size } else { 0 };
+    let client = Client::builder()
+        .connect_timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+    let counters: Vec<Arc<AtomicI64>> = segments
+        .iter()
+        .map(|segment| Arc::new(AtomicI64::new(segment.downloaded)))
+        .collect();
+
+    // JoinSet 被丢弃时会中止所有分段任务，暂停下载时不会残留连接
+    let limiter = task_limiter(download.id, download.speed_limit);
+    let mut tasks = JoinSet::new();
+    for (segment, counter) in segments.iter().zip(counters.iter()) {
+        if segment.is_finished() {
+            continue;
+        }
+        tasks.spawn(download_segment(
+            client.clone(),
+            url.to_string(),
+            download.referer.clone(),
+            segment.part_path(file),
+            segment.clone(),
+            counter.clone(),
+            limiter.clone(),
+        ));
+    }
+
+    // 连续多次低于最低速度时切换 CDN
+    let min_speed = CONFIG.lock().unwrap().mirror_min_speed * 1024;
+    let mut slow_ticks = 0;
+    let mut last_downloaded = segments.iter().map(|segment| segment.downloaded).sum::<i64>();
+
+    let update_interval = Duration::from_secs(1); // 每 1 秒更新一次
+    let mut ticker = tokio::time::interval(update_interval);
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            result = tasks.join_next() => {
+                match result {
+                    Some(Ok(Ok(_))) => {}
+                    Some(Ok(Err(err))) => return Err(err),
+                    Some(Err(err)) => return Err(SegmentError::Other(format!("segment task failed: {}", err))),
+                    None => break,
+                }
+            }
+            _ = ticker.tick() => {
+                for (segment, counter) in segments.iter_mut().zip(counters.iter()) {
+                    segment.downloaded = counter.load(Ordering::Relaxed);
+                }
+                let downloaded = segments.iter().map(|segment| segment.downloaded).sum::<i64>();
+                download.downloaded_size = offset + downloaded;
+                meter.update(download.downloaded_size);
+                app.emit("progress", DownloadProgress::new(download, stream, meter)).unwrap();
+                save_segments(segments).await.map_err(|e| SegmentError::Other(e.to_string()))?;
+                update_download_file(download).await.map_err(|e| SegmentError::Other(e.to_string()))?;
+
+                let speed = downloaded - last_downloaded;
+                last_downloaded = downloaded;
+                // 任务限速或全局限速生效时速度低不是 CDN 的问题
+                let throttled = limiter.is_throttling(update_interval) || GLOBAL_LIMITER.is_throttling(update_interval);
+                if min_speed > 0 && speed < min_speed && !throttled {
+                    slow_ticks += 1;
+                    if slow_ticks >= SLOW_TICKS_LIMIT {
+                        return Err(SegmentError::Slow(speed));
+                    }
+                } else {
+                    slow_ticks = 0;
+                }
+            }
+        }
+    }
+
+    for (segment, counter) in segments.iter_mut().zip(counters.iter()) {
+        segment.downloaded = counter.load(Ordering::Relaxed);
+    }
+
+    Ok(())
+}
+
+fn stream_url<'a>(download: &'a Download, stream: &str) -> &'a str {
+    if stream == "video" {
+        &download.video_url
+    } else {
+        &download.audio_url
+    }
+}
+
+// 主链接在前，备用链接在后
+fn stream_mirrors(download: &Download, stream: &str) -> Vec<String> {
+    let backups = if stream == "video" {
+        &download.video_backup_urls
+    } else {
+        &download.audio_backup_urls
+    };
+    let mut mirrors = vec![stream_url(download, stream).to_string()];
+    mirrors.extend(backups.iter().cloned());
+    mirrors
+}
+
+// upos 链接的 deadline 参数是过期的时间戳，预留一分钟余量
+fn is_url_expired(url: &str) -> bool {
+    let deadline = url
+        .split(['?', '&'])
+        .find_map(|param| param.strip_prefix("deadline="))
+        .and_then(|value| value.parse::<i64>().ok());
+    match deadline {
+        Some(deadline) => deadline <= chrono::Utc::now().timestamp() + 60,
+        None => false,
+    }
+}
+
+// 去掉域名和签名参数，同一个流刷新前后的路径不变
+fn url_path(url: &str) -> &str {
+    let url = url.split('?').next().unwrap_or_default();
+    match url.find("://") {
+        Some(index) => url[index + 3..].find('/').map_or("", |path| &url[index + 3 + path..]),
+        None => url,
+    }
+}
+
+// 优先选择路径相同的流，其次是清晰度和编码相同的，最后按序号选择
+fn pick_stream(download: &Download, streams: &[VideoStream]) -> Option<usize> {
+    streams.iter()
+        .position(|stream| url_path(&stream.base_url) == url_path(&download.video_url))
+        .or_else(|| streams.iter().position(|stream| download.qn != 0 && stream.matches(download.qn, download.codecid)))
+        .or_else(|| Some(download.quality as usize).filter(|&index| index < streams.len()))
+}
+
+// 下载来源当前的视频流和音频链接，仅下载音频时使用最高音质的音频流
+async fn get_source_streams(download: &Download) -> Result<(Vec<VideoStream>, String, Vec<String>), String> {
+    let audio_only = download.is_audio_only();
+    if !download.ep_id.is_empty() {
+        let episode = anime::get_episode_streams(&download.ep_id).await?;
+        if audio_only {
+            Ok((episode.video_streams, episode.best_audio_url, episode.best_audio_backup_urls))
+        } else {
+            Ok((episode.video_streams, episode.audio_url, episode.audio_backup_urls))
+        }
+    } else if !download.bvid.is_empty() && !download.cid.is_empty() {
+        let episode = video::get_episode_streams(&download.bvid, &download.cid).await?;
+        if audio_only {
+            Ok((episode.video_streams, episode.best_audio_url, episode.best_audio_backup_urls))
+        } else {
+            Ok((episode.video_streams, episode.audio_url, episode.audio_backup_urls))
+        }
+    } else {
+        Err(format!("download {} has no source to get streams", download.id))
+    }
+}
+
+// 按 qn 和 codecid 选择视频流，没有指定或者找不到时按设置中的规则自动选择
+async fn select_video_stream(download: &mut Download) -> Result<(), String> {
+    let (streams, _, _) = get_source_streams(download).await?;
+    let preference = CONFIG.lock().unwrap().quality.clone();
+    let index = streams
+        .iter()
+        .position(|stream| download.qn != 0 && stream.matches(download.qn, download.codecid))
+        .or_else(|| pick_video_stream(&streams, &preference))
+        .ok_or(format!("no video stream found for {}", download.file_name))?;
+
+    let stream = &streams[index];
+    download.video_url = stream.base_url.clone();
+    download.video_backup_urls = stream.backup_urls.clone();
+    download.video_size = stream.size;
+    download.qn = stream.qn;
+    download.codecid = stream.codecid;
+    download.quality = index as i32;
+
+    Ok(())
+}
+
+// 仅音频的任务使用来源中音质最好的音频流，保存格式由该音频流决定，不使用前端传入的格式
+// 没有来源时保存为 m4a，m4a 可以容纳 AAC 和 FLAC 音频
+async fn select_audio_stream(download: &mut Download) -> Result<(), String> {
+    let best = if !download.ep_id.is_empty() {
+        let episode = anime::get_episode_streams(&download.ep_id).await?;
+        Some((episode.best_audio_url, episode.best_audio_backup_urls, episode.best_audio_format))
+    } else if !download.bvid.is_empty() && !download.cid.is_empty() {
+        let episode = video::get_episode_streams(&download.bvid, &download.cid).await?;
+        Some((episode.best_audio_url, episode.best_audio_backup_urls, episode.best_audio_format))
+    } else {
+        None
+    };
+
+    match best {
+        Some((url, backup_urls, format)) if !url.is_empty() => {
+            download.audio_url = url;
+            download.audio_backup_urls = backup_urls;
+            download.audio_size = 0;
+            download.container = if format.is_empty() { "m4a".to_string() } else { format };
+        }
+        _ => download.container = "m4a".to_string(),
+    }
+
+    Ok(())
+}
+
+// 根据下载来源重新获取音视频链接，并确认和原来的文件大小一致
+async fn refresh_download_urls(download: &mut Download) -> Result<(), String> {
+    let (streams, audio_url, audio_backup_urls) = get_source_streams(download).await?;
+
+    if !download.video_url.is_empty() {
+        let index = pick_stream(download, &streams)
+            .ok_or(format!("no video stream found for download {}", download.id))?;
+        let stream = &streams[index];
+        if get_file_size(&stream.base_url, &download.referer).await? != download.video_size {
+            return Err(format!("video stream of download {} has changed", download.id));
+        }
+        download.video_url = stream.base_url.clone();
+        download.video_backup_urls = stream.backup_urls.clone();
+    }
+
+    if !download.audio_url.is_empty() {
+        if get_file_size(&audio_url, &download.referer).await? != download.audio_size {
+            return Err(format!("audio stream of download {} has changed", download.id));
+        }
+        download.audio_url = audio_url;
+        download.audio_backup_urls = audio_backup_urls;
+    }
+
+    update_download_file(download).await.map_err(|e| e.to_string())?;
 
     Ok(())
 }
@@ -488,11 +990,14 @@ pub async fn stop_downloading(id: i32) -> Result<(), String> {
         tx = map.remove(&id).unwrap();
     }
 
-    let mut download = get_download_file(id).await.unwrap();
-    download.status = "paused".to_string();
-    update_download_file(&download).await.unwrap();
+    // 接收端已经关闭说明任务已经结束，不修改它的状态
+    if tx.send(()).await.is_err() {
+        return Err("task already finished".to_string());
+    }
 
-    tx.send(()).await.unwrap();
+    let mut download = get_download_file(id).await.map_err(|e| e.to_string())?;
+    download.status = "paused".to_string();
+    update_download_file(&download).await.map_err(|e| e.to_string())?;
 
     Ok(())
 }
@@ -500,7 +1005,15 @@ pub async fn stop_downloading(id: i32) -> Result<(), String> {
 pub async fn check_download_init(app: AppHandle) {
     let downloadings = get_all_downloading_files().await.unwrap();
     for downloading in downloadings {
-        start_downloading(app.clone(), downloading.id).await.unwrap();
+        // 等待时间段的任务交给调度器
+        if downloading.status == "scheduled" {
+            continue;
+        }
+        if !is_download_allowed(&downloading) {
+            update_download_status(downloading.id, "scheduled").await.unwrap();
+            continue;
+        }
+        tokio::spawn(start_downloading(app.clone(), downloading.id));
     }
 }
 
@@ -515,7 +1028,7 @@ async fn get_file_size(url: &str, referer: &str) -> Result<i64, String> {
         .headers(headers2)
         .send()
         .await
-        .unwrap();
+        .map_err(|e| format!("request download url failed: {}", e))?;
 
     if !response.status().is_success() {
         return Err(format!("request download url failed, response:{:?}", response));
@@ -524,118 +1037,111 @@ async fn get_file_size(url: &str, referer: &str) -> Result<i64, String> {
     let content_range = response
         .headers()
         .get("Content-Range")
-        .ok_or("No Content-Range header found");
-    let content_range_str = content_range.unwrap().to_str().unwrap();
+        .ok_or("No Content-Range header found")?;
+    let content_range_str = content_range.to_str().map_err(|e| e.to_string())?;
     let file_total_size: i64 = content_range_str
         .split('/')
         .nth(1)
-        .ok_or("Invalid Content-Range format")
-        .unwrap()
+        .ok_or("Invalid Content-Range format")?
         .parse()
-        .unwrap();
+        .map_err(|_| "Invalid Content-Range format".to_string())?;
 
     Ok(file_total_size)
 }
 
-// async fn merge_file(app: &AppHandle, download: &mut Download) -> Result<(), String> {
-//     // 构造文件路径
-//     let video_file = download.file_path.replace(".mp4", "_video.mp4");
-//     let audio_file = download.file_path.replace(".mp4", "_audio.mp4");
-//
-//     // 使用 ffmpeg
-//     let sidecar_command = app.shell().sidecar("ffmpeg").unwrap();
-//
-//     // 设置参数
-//     let args = vec![
-//         "-i", &video_file,
-//         "-i", &audio_file,
-//         "-vcodec", "copy",
-//         "-acodec", "copy",
-//         &download.file_path,
-//     ];
-//
-//     // 执行命令
-//     let output = sidecar_command
-//         .args(args)
-//         .output()
-//         .await
-//         .unwrap();
-//
-//     // 处理输出
-//     if !output.status.success() {
-//         eprintln!("Error executing ffmpeg: {:?}", output.stderr);
-//         return Err("ffmpeg error".to_string());
-//     }
-//
-//     if Path::new(&video_file).exists() {
-//         match remove_file(video_file) {
-//             Ok(_) => {}
-//             Err(e) => eprintln!("Failed to delete video file: {}", e),
-//         }
-//     } else {
-//         println!("video file does not exist.");
-//     }
-//
-//     if Path::new(&audio_file).exists() {
-//         match remove_file(audio_file) {
-//             Ok(_) => {}
-//             Err(e) => eprintln!("Failed to delete audio file: {}", e),
-//         }
-//     } else {
-//         println!("audio file does not exist.");
-//     }
-//
-//     Ok(())
-// }
-async fn merge_file(app: &AppHandle, download: &mut Download) -> Result<(), String> {
-    unsafe {
-        let resource_path;
-        if cfg!(debug_assertions) {
-            // 开发环境
-            resource_path = app.path().resolve("bin/libffmpeg.dll", BaseDirectory::Resource).unwrap();
-        } else {
-            // 打包后的环境
-            resource_path = app.path().resolve("libffmpeg.dll", BaseDirectory::Resource).unwrap();
-        }
-        let libffmpeg = Library::new(resource_path).unwrap();
-        let merge_video_audio: Symbol<unsafe extern "C" fn(*const c_char, *const c_char, *const c_char) -> bool> = libffmpeg.get(b"merge_video_audio").unwrap();
-        let video_path = CString::new(download.file_path.replace(".mp4", "_video.mp4")).unwrap();
-        let audio_path = CString::new(download.file_path.replace(".mp4", "_audio.mp4")).unwrap();
-        let output_path = CString::new(download.file_path.clone()).unwrap();
-        let ret = merge_video_audio(video_path.as_ptr(), audio_path.as_ptr(), output_path.as_ptr());
-
-        if Path::new(&download.file_path.replace(".mp4", "_video.mp4")).exists() {
-            match remove_file(download.file_path.replace(".mp4", "_video.mp4")) {
-                Ok(_) => {}
-                Err(e) => eprintln!("Failed to delete video file: {}", e),
-            }
-        } else {
-            println!("video file does not exist.");
-        }
+// 下载封面图片，失败时不写入封面
+async fn get_cover(url: &str, referer: &str) -> Result<Vec<u8>, String> {
+    let response = Client::new()
+        .get(url)
+        .header(USER_AGENT, Agent)
+        .header(REFERER, referer)
+        .send()
+        .await
+        .map_err(|e| format!("request cover failed: {}", e))?;
+    if !response.status().is_success() {
+        return Err(format!("request cover failed, status: {}", response.status()));
+    }
+    let bytes = response.bytes().await.map_err(|e| format!("read cover failed: {}", e))?;
 
-        if Path::new(&download.file_path.replace(".mp4", "_audio.mp4")).exists() {
-            match remove_file(download.file_path.replace(".mp4", "_audio.mp4")) {
-                Ok(_) => {}
-                Err(e) => eprintln!("Failed to delete audio file: {}", e),
-            }
-        } else {
-            println!("audio file does not exist.");
+    Ok(bytes.to_vec())
+}
+
+async fn merge_file(download: &Download, inputs: Vec<String>, subtitles: Vec<SubtitleTrack>) -> Result<(), String> {
+    let mut cover = Vec::new();
+    if !download.metadata.cover.is_empty() {
+        match get_cover(&download.metadata.cover, &download.referer).await {
+            Ok(bytes) => cover = bytes,
+            Err(e) => eprintln!("Failed to download cover {}: {}", download.metadata.cover, e),
         }
+    }
+    let job = MergeJob {
+        inputs: inputs.clone(),
+        output: download.file_path.clone(),
+        container: download.container.clone(),
+        metadata: download.metadata.clone(),
+        cover,
+        subtitles,
+    };
+
+    // 合并需要读写整个文件，放到阻塞线程中执行
+    tokio::task::spawn_blocking(move || merger::merge(&job))
+        .await
+        .map_err(|e| format!("merge task failed: {}", e))??;
 
-        if !ret {
-            Err("failed to merge video and audio file".parse().unwrap())
-        } else {
-            Ok(())
+    // 合并失败时保留音视频文件，重试时不需要重新下载
+    for file in inputs {
+        if let Err(e) = remove_file(&file) {
+            eprintln!("Failed to delete {}: {}", file, e);
         }
     }
+
+    Ok(())
 }
 
 pub fn create_table(db_name: &str) -> Result<(Connection)> {
     let mut config = CONFIG.lock().unwrap();
     // 创建表格
     let conn = Connection::open(get_path_str("download.db"))?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS downloads (
+    conn.execute(CREATE_DOWNLOADS_TABLE, [])?;
+    // 旧版本数据库缺少的列
+    add_column_if_missing(&conn, "downloads", "bvid", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(&conn, "downloads", "cid", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(&conn, "downloads", "ep_id", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(&conn, "downloads", "quality", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "downloads", "video_backup_urls", "TEXT NOT NULL DEFAULT '[]'")?;
+    add_column_if_missing(&conn, "downloads", "audio_backup_urls", "TEXT NOT NULL DEFAULT '[]'")?;
+    add_column_if_missing(&conn, "downloads", "speed_limit", "INTEGER NULL")?;
+    add_column_if_missing(&conn, "downloads", "priority", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "downloads", "start_at", "TEXT NOT NULL DEFAULT ''")?;
+    add_column_if_missing(&conn, "downloads", "container", "TEXT NOT NULL DEFAULT 'mp4'")?;
+    add_column_if_missing(&conn, "downloads", "metadata", "TEXT NOT NULL DEFAULT '{}'")?;
+    add_column_if_missing(&conn, "downloads", "qn", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "downloads", "codecid", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(&conn, "downloads", "subtitles", "TEXT NOT NULL DEFAULT '[]'")?;
+    if add_column_if_missing(&conn, "downloads", "queue_position", "INTEGER NOT NULL DEFAULT 0")? {
+        // 已有任务按添加顺序排队
+        conn.execute("UPDATE downloads SET queue_position = id", [])?;
+    }
+    // 旧版本的 status 约束缺少新的状态，需要重建表
+    let sql: String = conn.query_row("SELECT sql FROM sqlite_master WHERE type = 'table' AND name = 'downloads'", [], |row| row.get(0))?;
+    if !sql.contains("'scheduled'") {
+        conn.execute_batch(&format!(
+            "BEGIN;
+            ALTER TABLE downloads RENAME TO downloads_old;
+            {};
+            INSERT INTO downloads ({columns}) SELECT {columns} FROM downloads_old;
+            DROP TABLE downloads_old;
+            COMMIT;",
+            CREATE_DOWNLOADS_TABLE,
+            columns = DOWNLOAD_COLUMNS,
+        ))?;
+    }
+    create_segment_table(&conn)?;
+
+    Ok(conn)
+}
+
+const CREATE_DOWNLOADS_TABLE: &str = "CREATE TABLE IF NOT EXISTS downloads (
             id              INTEGER PRIMARY KEY AUTOINCREMENT,
             video_url       TEXT NULL,
             audio_url       TEXT NULL,
@@ -646,12 +1152,36 @@ pub fn create_table(db_name: &str) -> Result<(Connection)> {
             audio_size      INTEGER,
             total_size      INTEGER,
             downloaded_size INTEGER NOT NULL,
-            status          TEXT NOT NULL CHECK(status IN ('downloading', 'completed', 'paused', 'failed')),
+            status          TEXT NOT NULL CHECK(status IN ('downloading', 'completed', 'paused', 'failed', 'queued', 'scheduled')),
             added_date      TEXT NOT NULL,
-            last_updated_date TEXT
-        )",
-        [],
-    )?;
+            last_updated_date TEXT,
+            bvid            TEXT NOT NULL DEFAULT '',
+            cid             TEXT NOT NULL DEFAULT '',
+            ep_id           TEXT NOT NULL DEFAULT '',
+            quality         INTEGER NOT NULL DEFAULT 0,
+            video_backup_urls TEXT NOT NULL DEFAULT '[]',
+            audio_backup_urls TEXT NOT NULL DEFAULT '[]',
+            speed_limit     INTEGER NULL,
+            queue_position  INTEGER NOT NULL DEFAULT 0,
+            priority        INTEGER NOT NULL DEFAULT 0,
+            start_at        TEXT NOT NULL DEFAULT '',
+            container       TEXT NOT NULL DEFAULT 'mp4',
+            metadata        TEXT NOT NULL DEFAULT '{}',
+            qn              INTEGER NOT NULL DEFAULT 0,
+            codecid         INTEGER NOT NULL DEFAULT 0,
+            subtitles       TEXT NOT NULL DEFAULT '[]'
+        )";
+
+// 返回是否新增了该列
+fn add_column_if_missing(conn: &Connection, table: &str, column: &str, definition: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let existed = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    if !existed {
+        conn.execute(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition), [])?;
+    }
 
-    Ok(conn)
+    Ok(!existed)
 }
@@ -0,0 +1,167 @@
+use std::fs::File;
+use std::io::{self, copy, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::merger::Metadata;
+use crate::mp4::{children, image_type, invalid, read_track, Track};
+
+// 元数据块类型
+const STREAMINFO: u8 = 0;
+const VORBIS_COMMENT: u8 = 4;
+const PICTURE: u8 = 6;
+// 块头中表示最后一个元数据块的标志
+const LAST_BLOCK: u8 = 0x80;
+// PICTURE 块中的封面类型
+const FRONT_COVER: u32 = 3;
+
+fn metadata_block(kind: u8, data: &[u8], last: bool) -> Vec<u8> {
+    let mut block = Vec::with_capacity(data.len() + 4);
+    block.push(if last { kind | LAST_BLOCK } else { kind });
+    block.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+    block.extend_from_slice(data);
+    block
+}
+
+// Vorbis 注释中的长度为小端序
+fn vorbis_comment(metadata: &Metadata) -> Option<Vec<u8>> {
+    let comments = [
+        ("TITLE", &metadata.title),
+        ("ARTIST", &metadata.artist),
+        ("DATE", &metadata.date),
+        ("DESCRIPTION", &metadata.description),
+    ]
+    .into_iter()
+    .filter(|(_, value)| !value.is_empty())
+    .map(|(key, value)| format!("{}={}", key, value))
+    .collect::<Vec<_>>();
+    if comments.is_empty() {
+        return None;
+    }
+
+    let vendor = "bilibili-downloader";
+    let mut data = Vec::new();
+    data.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    data.extend_from_slice(vendor.as_bytes());
+    data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+        data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+        data.extend_from_slice(comment.as_bytes());
+    }
+    Some(data)
+}
+
+// 封面图片，宽高和颜色深度填 0 由播放器自行解析
+fn picture(cover: &[u8]) -> Option<Vec<u8>> {
+    if cover.is_empty() {
+        return None;
+    }
+    let Some((_, mime_type)) = image_type(cover) else {
+        eprintln!("Unsupported cover image format, skipped");
+        return None;
+    };
+
+    let mut data = Vec::with_capacity(cover.len() + 64);
+    data.extend_from_slice(&FRONT_COVER.to_be_bytes());
+    data.extend_from_slice(&(mime_type.len() as u32).to_be_bytes());
+    data.extend_from_slice(mime_type.as_bytes());
+    // 描述、宽、高、颜色深度和索引色数量
+    data.extend_from_slice(&[0u8; 20]);
+    data.extend_from_slice(&(cover.len() as u32).to_be_bytes());
+    data.extend_from_slice(cover);
+    Some(data)
+}
+
+// dfLa 中保存的元数据块，返回类型和内容
+fn stream_blocks(track: &Track) -> io::Result<Vec<(u8, Vec<u8>)>> {
+    let (kind, entry) = track.sample_entry()?;
+    if &kind != b"fLaC" {
+        return Err(invalid(format!("{} is not a FLAC stream", track.path)));
+    }
+    let dfla = children(entry.get(28..).unwrap_or_default())?
+        .into_iter()
+        .find(|(kind, _, _)| kind == b"dfLa")
+        .map(|(_, payload, _)| payload)
+        .ok_or_else(|| invalid("missing dfLa box"))?;
+
+    let data = dfla.get(4..).unwrap_or_default();
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while pos + 4 <= data.len() {
+        let kind = data[pos] & !LAST_BLOCK;
+        let length = u32::from_be_bytes([0, data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+        let block = data.get(pos + 4..pos + 4 + length).ok_or_else(|| invalid("unexpected end of dfLa"))?;
+        blocks.push((kind, block.to_vec()));
+        pos += 4 + length;
+    }
+    if blocks.first().map(|(kind, _)| *kind) != Some(STREAMINFO) {
+        return Err(invalid("missing STREAMINFO block"));
+    }
+
+    Ok(blocks)
+}
+
+// 分片中的 STREAMINFO 没有总采样数，按轨道时长补上
+fn fill_total_samples(streaminfo: &mut [u8], track: &Track) {
+    if streaminfo.len() < 18 {
+        return;
+    }
+    let sample_rate = (streaminfo[10] as u32) << 12 | (streaminfo[11] as u32) << 4 | (streaminfo[12] as u32) >> 4;
+    let total = (streaminfo[13] as u64 & 0x0f) << 32 | u32::from_be_bytes([streaminfo[14], streaminfo[15], streaminfo[16], streaminfo[17]]) as u64;
+    if total != 0 || sample_rate != track.timescale {
+        return;
+    }
+
+    let total = track.duration().min(0xf_ffff_ffff);
+    streaminfo[13] = (streaminfo[13] & 0xf0) | (total >> 32) as u8;
+    streaminfo[14..18].copy_from_slice(&(total as u32).to_be_bytes());
+}
+
+// 将 fMP4 中的 FLAC 帧写为 .flac 文件，标签写入 VORBIS_COMMENT，封面写入 PICTURE
+pub fn write_flac(track: &Track, output: &str, metadata: &Metadata, cover: &[u8]) -> io::Result<()> {
+    let mut blocks = stream_blocks(track)?;
+    blocks.retain(|(kind, _)| *kind != VORBIS_COMMENT && *kind != PICTURE);
+    fill_total_samples(&mut blocks[0].1, track);
+    if let Some(comment) = vorbis_comment(metadata) {
+        blocks.push((VORBIS_COMMENT, comment));
+    }
+    if let Some(picture) = picture(cover) {
+        blocks.push((PICTURE, picture));
+    }
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    writer.write_all(b"fLaC")?;
+    let count = blocks.len();
+    for (i, (kind, data)) in blocks.iter().enumerate() {
+        writer.write_all(&metadata_block(*kind, data, i + 1 == count))?;
+    }
+
+    // 每个样本是一个完整的 FLAC 帧，连续的样本一次复制
+    let mut input = File::open(&track.path)?;
+    let samples = &track.samples;
+    let mut i = 0;
+    while i < samples.len() {
+        let start = samples[i].offset;
+        let mut end = start + samples[i].size as u64;
+        i += 1;
+        while i < samples.len() && samples[i].offset == end {
+            end += samples[i].size as u64;
+            i += 1;
+        }
+        input.seek(SeekFrom::Start(start))?;
+        let copied = copy(&mut Read::by_ref(&mut input).take(end - start), &mut writer)?;
+        if copied != end - start {
+            return Err(invalid(format!("{} ended early", track.path)));
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+// 将无损音频流转换为 FLAC 文件
+pub fn remux(inputs: &[&str], output: &str, metadata: &Metadata, cover: &[u8]) -> Result<(), String> {
+    let [input] = inputs else {
+        return Err(format!("flac output needs exactly one audio stream, got {}", inputs.len()));
+    };
+    let track = read_track(input).map_err(|e| format!("failed to read {}: {}", input, e))?;
+    write_flac(&track, output, metadata, cover).map_err(|e| format!("failed to write {}: {}", output, e))
+}
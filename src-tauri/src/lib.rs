@@ -2,29 +2,48 @@ use std::error::Error;
 use std::fs::OpenOptions;
 use std::future::Future;
 use std::io::Write;
-use regex::Regex;
 use reqwest::Client;
 use scraper::{Html, Selector};
 use serde_json::Value;
 use tauri::{AppHandle, Emitter};
 use tauri_plugin_shell::ShellExt;
 
-use crate::anime::{Anime, check_ep_id, get_anime_info};
+use crate::anime::{Anime, Episode, EpisodeRef, get_anime_info, resolve_episodes};
 // Learn more about Tauri commands at https://tauri.app/v1/guides/features/command
 use crate::config::{BiliConfig, CONFIG, create_default_config, read_config, save_config};
-use crate::download::{add_download_file, check_download_init, delete_download_file, Download, get_all_downloaded_files, get_all_downloading_files, search_downloads, start_downloading, stop_downloading};
+use crate::download::{add_download_file, check_download_init, delete_download_file, Download, get_all_downloaded_files, get_all_downloading_files, search_downloads, start_downloading, stop_downloading, update_speed_limit, update_start_at};
+use crate::disk::run_disk_monitor;
+use crate::scheduler::run_scheduler;
+use crate::limiter::update_global_speed_limit;
+use crate::merger::{init_mergers, merge_backends, MergeBackend};
 use crate::path::{get_path_absolute, get_unique_file_path};
+use crate::queue::{move_download, QueueMove, set_priority};
+use crate::resolver::{resolve, Target};
 use crate::utils::{create_res, create_res_err, create_res_ok, Response};
 use crate::video::{get_video_info, Video};
 
+mod bvid;
 mod config;
+mod danmaku;
+mod disk;
 mod download;
+mod flac;
+mod limiter;
+mod merger;
+mod mkv;
+mod mp4;
 mod path;
+mod queue;
+mod resolver;
+mod scheduler;
+mod segment;
+mod stream;
+mod subtitle;
 mod utils;
 mod anime;
 mod video;
+mod wbi;
 
-const DANMU_URL: &str = "https://api.bilibili.com/x/v1/dm/list.so?oid=";
 const Agent: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/126.0.0.0 Safari/537.36 Edg/126.0.0.0";
 
 #[tauri::command]
@@ -46,6 +65,15 @@ fn update_config(config: BiliConfig) -> Response<String> {
     }
 }
 
+#[tauri::command]
+async fn get_merge_backends() -> Response<Vec<MergeBackend>> {
+    // 检查外部程序需要启动进程
+    match tauri::async_runtime::spawn_blocking(merge_backends).await {
+        Ok(backends) => create_res_ok(backends),
+        Err(err) => create_res(Vec::new(), format!("detect merge backends failed: [{:?}].", err)),
+    }
+}
+
 #[tauri::command]
 async fn get_downloading_files() -> Response<Vec<Download>> {
     match get_all_downloading_files().await {
@@ -111,6 +139,46 @@ async fn stop_downloading_file(id: i32) -> Response<String> {
     }
 }
 
+#[tauri::command]
+fn set_speed_limit(limit: i64) -> Response<String> {
+    match update_global_speed_limit(limit) {
+        Ok(_) => create_res_ok("ok".to_string()),
+        Err(err) => create_res_err(format!("set speed limit failed: [{:?}].", err)),
+    }
+}
+
+#[tauri::command]
+async fn set_download_speed_limit(id: i32, limit: Option<i64>) -> Response<String> {
+    match update_speed_limit(id, limit).await {
+        Ok(_) => create_res_ok("ok".to_string()),
+        Err(err) => create_res_err(format!("set download speed limit failed: [{:?}].", err)),
+    }
+}
+
+#[tauri::command]
+async fn set_download_start_at(app: AppHandle, id: i32, start_at: String) -> Response<String> {
+    match update_start_at(&app, id, start_at).await {
+        Ok(_) => create_res_ok("ok".to_string()),
+        Err(err) => create_res_err(format!("set download start time failed: [{:?}].", err)),
+    }
+}
+
+#[tauri::command]
+async fn move_download_in_queue(id: i32, direction: QueueMove) -> Response<String> {
+    match move_download(id, direction).await {
+        Ok(_) => create_res_ok("ok".to_string()),
+        Err(err) => create_res_err(format!("move download failed: [{:?}].", err)),
+    }
+}
+
+#[tauri::command]
+async fn set_download_priority(id: i32, priority: i32) -> Response<String> {
+    match set_priority(id, priority).await {
+        Ok(_) => create_res_ok("ok".to_string()),
+        Err(err) => create_res_err(format!("set download priority failed: [{:?}].", err)),
+    }
+}
+
 #[tauri::command]
 async fn open_file_directory(app: AppHandle, path: String) -> Result<(), String> {
     let command = match std::env::consts::OS {
@@ -140,34 +208,58 @@ async fn open_file_directory(app: AppHandle, path: String) -> Result<(), String>
 
 #[tauri::command]
 fn get_animates(ep_id: &str) -> Response<Anime> {
-    match tokio::runtime::Runtime::new().unwrap().block_on(get_anime_info(ep_id)) {
+    let result = tokio::runtime::Runtime::new().unwrap().block_on(async {
+        let target = resolve(ep_id).await?;
+        get_anime_info(&target).await
+    });
+    match result {
         Ok(anime) => create_res_ok(anime),
         Err(err) => create_res(Anime::default(), err)
     }
 }
 
+// 获取选中剧集的音视频链接和字幕，解析失败的剧集返回空的链接
+#[tauri::command]
+async fn get_anime_episodes(episodes: Vec<EpisodeRef>) -> Response<Vec<Episode>> {
+    let mut errors = Vec::new();
+    let episodes = resolve_episodes(episodes)
+        .await
+        .into_iter()
+        .map(|result| result.unwrap_or_else(|err| {
+            errors.push(err);
+            Episode::default()
+        }))
+        .collect();
+    create_res(episodes, errors.join("; "))
+}
+
 #[tauri::command]
 fn get_videos(bv_id: &str) -> Response<Video> {
-    let mut id = bv_id;
-    if bv_id.starts_with("http") {
-        // 定义正则表达式，匹配以 "BV" 开头后面跟随一串字母和数字的字符串
-        let re = Regex::new(r"/(BV[a-zA-Z0-9]+)/").unwrap();
-
-        // 查找并提取匹配的内容
-        if let Some(caps) = re.captures(bv_id) {
-            if let Some(bv) = caps.get(1) {
-                id = bv.as_str();
+    let result = tokio::runtime::Runtime::new().unwrap().block_on(async {
+        match resolve(bv_id).await? {
+            Target::Video { id, page } => {
+                let mut video = get_video_info(&id).await?;
+                video.page = page;
+                Ok(video)
             }
-        } else {
-            create_res(Video::default(), "链接匹配 BV 号失败".to_string());
+            target => Err(format!("不是视频的链接: {:?}", target)),
         }
-    }
-    match tokio::runtime::Runtime::new().unwrap().block_on(get_video_info(id)) {
+    });
+    match result {
         Ok(video) => create_res_ok(video),
         Err(err) => create_res(Video::default(), err)
     }
 }
 
+// 解析用户输入的编号或链接，前端根据类型打开对应的页面
+#[tauri::command]
+async fn resolve_input(input: String) -> Response<Option<Target>> {
+    match resolve(&input).await {
+        Ok(target) => create_res_ok(Some(target)),
+        Err(err) => create_res(None, err),
+    }
+}
+
 #[tauri::command]
 async fn download_cover(url: String) -> Response<String> {
     let client = Client::new();
@@ -213,16 +305,22 @@ pub fn run() {
         .plugin(tauri_plugin_notification::init())
         .setup(|app| {
             let app_handle = app.handle().clone();
+            init_mergers(&app_handle);
 
+            let scheduler_handle = app_handle.clone();
+            let monitor_handle = app_handle.clone();
             tauri::async_runtime::spawn(async move {
                 check_download_init(app_handle).await;
             });
+            tauri::async_runtime::spawn(run_scheduler(scheduler_handle));
+            tauri::async_runtime::spawn(run_disk_monitor(monitor_handle));
 
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
             get_config,
             update_config,
+            get_merge_backends,
             get_downloading_files,
             get_downloaded_files,
             search_downloaded,
@@ -230,9 +328,16 @@ pub fn run() {
             delete_download,
             start_downloading_file,
             stop_downloading_file,
+            set_speed_limit,
+            set_download_speed_limit,
+            move_download_in_queue,
+            set_download_priority,
+            set_download_start_at,
             open_file_directory,
             get_animates,
+            get_anime_episodes,
             get_videos,
+            resolve_input,
             download_cover
         ])
         .run(tauri::generate_context!())
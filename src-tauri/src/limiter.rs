@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use tokio::time::Instant;
+
+use crate::config::{BiliConfig, CONFIG, save_config};
+
+// 令牌桶限速器，rate 为每秒字节数，0 表示不限速
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    rate: i64,
+    tokens: f64,
+    last: Instant,
+    // 最近一次因限速需要等待时，等待结束的时间
+    throttled_until: Option<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: i64) -> Self {
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate as f64,
+                last: Instant::now(),
+                throttled_until: None,
+            }),
+        }
+    }
+
+    // 修改速度后立即对正在下载的任务生效
+    pub fn set_rate(&self, rate: i64) {
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.rate = rate;
+        bucket.tokens = bucket.tokens.min(rate as f64);
+    }
+
+    // 取出 amount 个令牌，不足时先透支，再等待补齐
+    pub async fn acquire(&self, amount: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            if bucket.rate <= 0 {
+                return;
+            }
+
+            // 桶的容量为一秒的流量
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            bucket.last = now;
+            bucket.tokens = (bucket.tokens + elapsed * bucket.rate as f64).min(bucket.rate as f64);
+            bucket.tokens -= amount as f64;
+
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            let wait = Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64);
+            bucket.throttled_until = Some(now + wait);
+            wait
+        };
+
+        tokio::time::sleep(wait).await;
+    }
+
+    // 在 within 时间内是否因限速等待过，此时速度低是限速造成的
+    pub fn is_throttling(&self, within: Duration) -> bool {
+        let bucket = self.bucket.lock().unwrap();
+        bucket.rate > 0 && bucket.throttled_until.is_some_and(|until| until + within >= Instant::now())
+    }
+}
+
+lazy_static! {
+    pub static ref GLOBAL_LIMITER: Arc<RateLimiter> = Arc::new(RateLimiter::new(CONFIG.lock().unwrap().speed_limit * 1024));
+    static ref TASK_LIMITERS: Mutex<HashMap<i32, Arc<RateLimiter>>> = Mutex::new(HashMap::new());
+}
+
+// 获取任务的限速器，limit 为 KB/s，None 表示只受全局限速
+// limit 只在创建时使用，之后的修改由 set_task_speed_limit 生效，重新开始分段下载时不会还原
+pub fn task_limiter(id: i32, limit: Option<i64>) -> Arc<RateLimiter> {
+    TASK_LIMITERS.lock().unwrap()
+        .entry(id)
+        .or_insert_with(|| Arc::new(RateLimiter::new(limit.unwrap_or(0) * 1024)))
+        .clone()
+}
+
+// 修改正在下载的任务的限速，任务未开始时不需要处理
+pub fn set_task_speed_limit(id: i32, limit: Option<i64>) {
+    if let Some(limiter) = TASK_LIMITERS.lock().unwrap().get(&id) {
+        limiter.set_rate(limit.unwrap_or(0) * 1024);
+    }
+}
+
+pub fn remove_task_limiter(id: i32) {
+    TASK_LIMITERS.lock().unwrap().remove(&id);
+}
+
+// 修改全局限速并保存到配置文件
+pub fn update_global_speed_limit(limit: i64) -> std::io::Result<()> {
+    let config: BiliConfig = {
+        let mut config = CONFIG.lock().unwrap().clone();
+        config.speed_limit = limit;
+        config
+    };
+    save_config(config)
+}
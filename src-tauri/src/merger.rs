@@ -0,0 +1,316 @@
+use std::env;
+use std::ffi::{c_char, CString};
+use std::fs::remove_file;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use libloading::{Library, Symbol};
+use serde::{Deserialize, Serialize};
+use tauri::path::BaseDirectory;
+use tauri::{AppHandle, Manager};
+
+use crate::config::CONFIG;
+use crate::subtitle::{iso_language, SubtitleTrack};
+use crate::{flac, mkv, mp4};
+
+lazy_static! {
+    // 启动时找到的随应用分发的合并库
+    static ref NATIVE_LIBRARY: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+// 写入输出文件的标签，cover 为封面图片的链接
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Metadata {
+    pub(crate) title: String,
+    pub(crate) artist: String,
+    pub(crate) date: String,
+    pub(crate) description: String,
+    pub(crate) cover: String,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_empty() && self.artist.is_empty() && self.date.is_empty() && self.description.is_empty() && self.cover.is_empty()
+    }
+}
+
+// 一次合并的输入流、输出文件和封装格式（mp4、mkv、m4a 或 flac），cover 为下载好的封面图片
+#[derive(Debug, Clone)]
+pub struct MergeJob {
+    pub(crate) inputs: Vec<String>,
+    pub(crate) output: String,
+    pub(crate) container: String,
+    pub(crate) metadata: Metadata,
+    pub(crate) cover: Vec<u8>,
+    // 作为软字幕写入的字幕轨道
+    pub(crate) subtitles: Vec<SubtitleTrack>,
+}
+
+impl MergeJob {
+    fn inputs(&self) -> Vec<&str> {
+        self.inputs.iter().map(|input| input.as_str()).collect()
+    }
+}
+
+// 合并音视频流的方式，失败时依次尝试下一个
+pub trait Merger: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn path(&self) -> String;
+    fn is_available(&self) -> bool;
+    // 不支持的任务直接跳过
+    fn supports(&self, _job: &MergeJob) -> bool {
+        true
+    }
+    fn merge(&self, job: &MergeJob) -> Result<(), String>;
+}
+
+// 展示在设置页面的合并方式
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MergeBackend {
+    pub(crate) name: String,
+    pub(crate) path: String,
+    pub(crate) available: bool,
+}
+
+// 内置的 MP4、MKV 和 FLAC 重新封装，不依赖外部程序
+pub struct BuiltinMerger;
+
+impl Merger for BuiltinMerger {
+    fn name(&self) -> &'static str {
+        "builtin"
+    }
+
+    fn path(&self) -> String {
+        String::new()
+    }
+
+    fn is_available(&self) -> bool {
+        true
+    }
+
+    fn merge(&self, job: &MergeJob) -> Result<(), String> {
+        match job.container.as_str() {
+            "mkv" => mkv::remux(&job.inputs(), &job.output, &job.metadata, &job.cover, &job.subtitles),
+            "flac" => flac::remux(&job.inputs(), &job.output, &job.metadata, &job.cover),
+            _ => mp4::remux(&job.inputs(), &job.output, &job.metadata, &job.cover, &job.subtitles),
+        }
+    }
+}
+
+// 导出 merge_video_audio 的 ffmpeg 动态库，按系统使用 .dll、.so 或 .dylib
+pub struct NativeMerger {
+    library: PathBuf,
+}
+
+impl Merger for NativeMerger {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn path(&self) -> String {
+        self.library.to_string_lossy().to_string()
+    }
+
+    fn is_available(&self) -> bool {
+        unsafe {
+            match Library::new(&self.library) {
+                Ok(library) => library.get::<Symbol<unsafe extern "C" fn()>>(b"merge_video_audio").is_ok(),
+                Err(_) => false,
+            }
+        }
+    }
+
+    // 动态库只能合并一个视频和一个音频，封装格式由输出文件的扩展名决定
+    fn supports(&self, job: &MergeJob) -> bool {
+        job.inputs.len() == 2 && job.subtitles.is_empty()
+    }
+
+    fn merge(&self, job: &MergeJob) -> Result<(), String> {
+        let video = CString::new(job.inputs[0].as_str()).map_err(|e| e.to_string())?;
+        let audio = CString::new(job.inputs[1].as_str()).map_err(|e| e.to_string())?;
+        let output = CString::new(job.output.as_str()).map_err(|e| e.to_string())?;
+        let ret = unsafe {
+            let library = Library::new(&self.library).map_err(|e| format!("failed to load {}: {}", self.path(), e))?;
+            let merge_video_audio: Symbol<unsafe extern "C" fn(*const c_char, *const c_char, *const c_char) -> bool> = library
+                .get(b"merge_video_audio")
+                .map_err(|e| format!("merge_video_audio not found in {}: {}", self.path(), e))?;
+            merge_video_audio(video.as_ptr(), audio.as_ptr(), output.as_ptr())
+        };
+
+        if ret {
+            Ok(())
+        } else {
+            Err("failed to merge video and audio file".to_string())
+        }
+    }
+}
+
+// 调用 ffmpeg 命令行，来自 PATH 或者设置中指定的路径
+pub struct FfmpegMerger {
+    name: &'static str,
+    program: PathBuf,
+}
+
+impl Merger for FfmpegMerger {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn path(&self) -> String {
+        self.program.to_string_lossy().to_string()
+    }
+
+    fn is_available(&self) -> bool {
+        Command::new(&self.program)
+            .arg("-version")
+            .output()
+            .map(|output| output.status.success())
+            .unwrap_or(false)
+    }
+
+    // 封面需要作为额外的输入写入，带封面的任务交给内置封装
+    fn supports(&self, job: &MergeJob) -> bool {
+        job.cover.is_empty()
+    }
+
+    fn merge(&self, job: &MergeJob) -> Result<(), String> {
+        let mut command = Command::new(&self.program);
+        command.args(["-y", "-loglevel", "error"]);
+        for input in &job.inputs {
+            command.arg("-i").arg(input);
+        }
+        for subtitle in &job.subtitles {
+            command.arg("-i").arg(&subtitle.srt);
+        }
+        for i in 0..job.inputs.len() + job.subtitles.len() {
+            command.arg("-map").arg(i.to_string());
+        }
+        command.args(["-c", "copy"]);
+        if !job.subtitles.is_empty() {
+            // MP4 中的字幕只能是 mov_text
+            command.args(["-c:s", if job.container == "mkv" { "srt" } else { "mov_text" }]);
+            for (i, subtitle) in job.subtitles.iter().enumerate() {
+                command.arg(format!("-metadata:s:s:{}", i)).arg(format!("language={}", iso_language(&subtitle.lan)));
+            }
+        }
+        for (key, value) in [
+            ("title", &job.metadata.title),
+            ("artist", &job.metadata.artist),
+            ("date", &job.metadata.date),
+            ("description", &job.metadata.description),
+        ] {
+            if !value.is_empty() {
+                command.arg("-metadata").arg(format!("{}={}", key, value));
+            }
+        }
+        if job.container == "mp4" || job.container == "m4a" {
+            command.args(["-movflags", "+faststart"]);
+        }
+        let result = command
+            .arg(&job.output)
+            .output()
+            .map_err(|e| format!("failed to run {}: {}", self.path(), e))?;
+
+        if !result.status.success() {
+            return Err(format!("ffmpeg exited with {}: {}", result.status, String::from_utf8_lossy(&result.stderr).trim()));
+        }
+
+        Ok(())
+    }
+}
+
+fn native_library_name() -> String {
+    format!("libffmpeg.{}", env::consts::DLL_EXTENSION)
+}
+
+// 在 PATH 中查找 ffmpeg
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    let file = format!("{}{}", program, env::consts::EXE_SUFFIX);
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(&file))
+        .find(|path| path.is_file())
+}
+
+// 按优先级排列的合并方式：设置中指定的 ffmpeg、内置封装、动态库、PATH 中的 ffmpeg
+fn mergers() -> Vec<Box<dyn Merger>> {
+    let mut mergers: Vec<Box<dyn Merger>> = Vec::new();
+
+    let ffmpeg_path = CONFIG.lock().unwrap().ffmpeg_path.clone();
+    if !ffmpeg_path.is_empty() {
+        mergers.push(Box::new(FfmpegMerger {
+            name: "ffmpeg (config)",
+            program: PathBuf::from(ffmpeg_path),
+        }));
+    }
+    mergers.push(Box::new(BuiltinMerger));
+    if let Some(library) = NATIVE_LIBRARY.lock().unwrap().clone() {
+        mergers.push(Box::new(NativeMerger { library }));
+    }
+    if let Some(program) = find_in_path("ffmpeg") {
+        mergers.push(Box::new(FfmpegMerger {
+            name: "ffmpeg (PATH)",
+            program,
+        }));
+    }
+
+    mergers
+}
+
+// 启动时查找随应用分发的动态库，并打印可用的合并方式
+pub fn init_mergers(app: &AppHandle) {
+    let resource = if cfg!(debug_assertions) {
+        // 开发环境
+        format!("bin/{}", native_library_name())
+    } else {
+        // 打包后的环境
+        native_library_name()
+    };
+    if let Ok(path) = app.path().resolve(resource, BaseDirectory::Resource) {
+        if path.exists() {
+            *NATIVE_LIBRARY.lock().unwrap() = Some(path);
+        }
+    }
+
+    for backend in merge_backends() {
+        println!("Merge backend {} {}: {}", backend.name, backend.path, if backend.available { "available" } else { "unavailable" });
+    }
+}
+
+pub fn merge_backends() -> Vec<MergeBackend> {
+    mergers()
+        .iter()
+        .map(|merger| MergeBackend {
+            name: merger.name().to_string(),
+            path: merger.path(),
+            available: merger.is_available(),
+        })
+        .collect()
+}
+
+// 依次尝试可用的合并方式，失败时删除不完整的输出
+pub fn merge(job: &MergeJob) -> Result<(), String> {
+    let output = &job.output;
+    let mut errors = Vec::new();
+    for merger in mergers() {
+        if !merger.supports(job) || !merger.is_available() {
+            continue;
+        }
+        match merger.merge(job) {
+            Ok(_) => return Ok(()),
+            Err(err) => {
+                eprintln!("Merge backend {} failed: {}", merger.name(), err);
+                if Path::new(output).exists() {
+                    if let Err(e) = remove_file(output) {
+                        eprintln!("Failed to delete {}: {}", output, e);
+                    }
+                }
+                errors.push(format!("{}: {}", merger.name(), err));
+            }
+        }
+    }
+
+    Err(format!("all merge backends failed [{}]", errors.join("; ")))
+}
@@ -0,0 +1,670 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::merger::Metadata;
+use crate::mp4::{children, image_type, interleave, invalid, read_track, read_u16, read_u32, Track};
+use crate::subtitle::{remove_text_tracks, text_tracks, SubtitleTrack};
+
+// 元素 ID
+const EBML: u32 = 0x1A45DFA3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42F7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42F2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42F3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x18538067;
+const SEEK_HEAD: u32 = 0x114D9B74;
+const SEEK: u32 = 0x4DBB;
+const SEEK_ID: u32 = 0x53AB;
+const SEEK_POSITION: u32 = 0x53AC;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const DURATION: u32 = 0x4489;
+const MUXING_APP: u32 = 0x4D80;
+const WRITING_APP: u32 = 0x5741;
+const TITLE: u32 = 0x7BA9;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_UID: u32 = 0x73C5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9C;
+const LANGUAGE: u32 = 0x22B59C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const AUDIO: u32 = 0xE1;
+const SAMPLING_FREQUENCY: u32 = 0xB5;
+const CHANNELS: u32 = 0x9F;
+const BIT_DEPTH: u32 = 0x6264;
+const CLUSTER: u32 = 0x1F43B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const BLOCK_DURATION: u32 = 0x9B;
+const CUES: u32 = 0x1C53BB6B;
+const CUE_POINT: u32 = 0xBB;
+const CUE_TIME: u32 = 0xB3;
+const CUE_TRACK_POSITIONS: u32 = 0xB7;
+const CUE_TRACK: u32 = 0xF7;
+const CUE_CLUSTER_POSITION: u32 = 0xF1;
+const TAGS: u32 = 0x1254C367;
+const TAG: u32 = 0x7373;
+const TARGETS: u32 = 0x63C0;
+const TARGET_TYPE_VALUE: u32 = 0x68CA;
+const SIMPLE_TAG: u32 = 0x67C8;
+const TAG_NAME: u32 = 0x45A3;
+const TAG_STRING: u32 = 0x4487;
+const ATTACHMENTS: u32 = 0x1941A469;
+const ATTACHED_FILE: u32 = 0x61A7;
+const FILE_NAME: u32 = 0x466E;
+const FILE_MIME_TYPE: u32 = 0x4660;
+const FILE_DATA: u32 = 0x465C;
+const FILE_UID: u32 = 0x46AE;
+
+// 关键帧之间超过该时长（毫秒）时开始新的 Cluster，SimpleBlock 的相对时间最多 16 位
+const CLUSTER_DURATION: i64 = 1000;
+const MAX_CLUSTER_DURATION: i64 = 30000;
+// 事后回填的元素长度固定使用 8 字节
+const UNKNOWN_SIZE_LENGTH: usize = 8;
+
+// Matroska 中的轨道信息
+struct TrackInfo {
+    codec_id: &'static str,
+    codec_private: Vec<u8>,
+    kind: TrackKind,
+}
+
+enum TrackKind {
+    Video { width: u16, height: u16 },
+    Audio { channels: u16, sample_rate: u32, bit_depth: u16 },
+    Subtitle { language: String },
+}
+
+fn write_id(out: &mut Vec<u8>, id: u32) {
+    let bytes = id.to_be_bytes();
+    let skip = bytes.iter().position(|b| *b != 0).unwrap_or(3);
+    out.extend_from_slice(&bytes[skip..]);
+}
+
+fn write_size(out: &mut Vec<u8>, size: u64) {
+    let mut length = 1;
+    while length < 8 && size >= (1u64 << (7 * length)) - 1 {
+        length += 1;
+    }
+    write_size_with_length(out, size, length);
+}
+
+fn write_size_with_length(out: &mut Vec<u8>, size: u64, length: usize) {
+    let value = size | (1u64 << (7 * length));
+    out.extend_from_slice(&value.to_be_bytes()[8 - length..]);
+}
+
+fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 12);
+    write_id(&mut out, id);
+    write_size(&mut out, payload.len() as u64);
+    out.extend_from_slice(payload);
+    out
+}
+
+fn uint_element(id: u32, value: u64) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().position(|b| *b != 0).unwrap_or(7);
+    element(id, &bytes[skip..])
+}
+
+fn float_element(id: u32, value: f64) -> Vec<u8> {
+    element(id, &value.to_be_bytes())
+}
+
+fn string_element(id: u32, value: &str) -> Vec<u8> {
+    element(id, value.as_bytes())
+}
+
+// 读取 MPEG-4 描述符的长度，每个字节的最高位表示后面还有
+fn read_descriptor(data: &[u8], pos: &mut usize) -> io::Result<(u8, usize)> {
+    let tag = *data.get(*pos).ok_or_else(|| invalid("unexpected end of esds"))?;
+    *pos += 1;
+    let mut length = 0usize;
+    for _ in 0..4 {
+        let byte = *data.get(*pos).ok_or_else(|| invalid("unexpected end of esds"))?;
+        *pos += 1;
+        length = (length << 7) | (byte & 0x7f) as usize;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    Ok((tag, length))
+}
+
+// 从 esds 中取出 AAC 的 AudioSpecificConfig
+fn aac_config(esds: &[u8]) -> io::Result<Vec<u8>> {
+    let mut pos = 4;
+    let (tag, _) = read_descriptor(esds, &mut pos)?;
+    if tag != 0x03 {
+        return Err(invalid("missing ES descriptor"));
+    }
+    let flags = *esds.get(pos + 2).ok_or_else(|| invalid("unexpected end of esds"))?;
+    pos += 3;
+    if flags & 0x80 != 0 {
+        pos += 2;
+    }
+    if flags & 0x40 != 0 {
+        pos += 1 + *esds.get(pos).unwrap_or(&0) as usize;
+    }
+    if flags & 0x20 != 0 {
+        pos += 2;
+    }
+
+    let (tag, _) = read_descriptor(esds, &mut pos)?;
+    if tag != 0x04 {
+        return Err(invalid("missing decoder config descriptor"));
+    }
+    pos += 13;
+    let (tag, length) = read_descriptor(esds, &mut pos)?;
+    if tag != 0x05 {
+        return Err(invalid("missing decoder specific info"));
+    }
+    esds.get(pos..pos + length)
+        .map(|config| config.to_vec())
+        .ok_or_else(|| invalid("unexpected end of esds"))
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> io::Result<&'a [u8]> {
+    children(data)?
+        .into_iter()
+        .find(|(k, _, _)| k == kind)
+        .map(|(_, payload, _)| payload)
+        .ok_or_else(|| invalid(format!("missing {} box", String::from_utf8_lossy(kind))))
+}
+
+// 根据样本描述确定 Matroska 的编码 ID 和解码参数
+fn track_info(track: &Track) -> io::Result<TrackInfo> {
+    let (kind, entry) = track.sample_entry()?;
+    if track.is_video() {
+        // VisualSampleEntry 的固定字段共 78 字节
+        let boxes = entry.get(78..).ok_or_else(|| invalid("visual sample entry is too short"))?;
+        let (codec_id, codec_private) = match &kind {
+            b"avc1" | b"avc3" => ("V_MPEG4/ISO/AVC", child(boxes, b"avcC")?.to_vec()),
+            b"hvc1" | b"hev1" => ("V_MPEGH/ISO/HEVC", child(boxes, b"hvcC")?.to_vec()),
+            b"av01" => ("V_AV1", child(boxes, b"av1C")?.to_vec()),
+            _ => return Err(invalid(format!("unsupported video codec {}", String::from_utf8_lossy(&kind)))),
+        };
+        return Ok(TrackInfo {
+            codec_id,
+            codec_private,
+            kind: TrackKind::Video {
+                width: read_u16(entry, 24)?,
+                height: read_u16(entry, 26)?,
+            },
+        });
+    }
+
+    // 字幕轨道的样本为两字节长度加 UTF-8 文字，写入时去掉长度
+    if &kind == b"tx3g" {
+        return Ok(TrackInfo {
+            codec_id: "S_TEXT/UTF8",
+            codec_private: Vec::new(),
+            kind: TrackKind::Subtitle { language: subtitle_language(track) },
+        });
+    }
+
+    // AudioSampleEntry 的固定字段共 28 字节
+    let boxes = entry.get(28..).ok_or_else(|| invalid("audio sample entry is too short"))?;
+    let (codec_id, codec_private) = match &kind {
+        b"mp4a" => ("A_AAC", aac_config(child(boxes, b"esds")?)?),
+        b"ec-3" => ("A_EAC3", Vec::new()),
+        b"ac-3" => ("A_AC3", Vec::new()),
+        b"fLaC" => {
+            // dfLa 中是 FLAC 的元数据块，加上文件头即为 CodecPrivate
+            let mut private = b"fLaC".to_vec();
+            private.extend_from_slice(child(boxes, b"dfLa")?.get(4..).unwrap_or_default());
+            ("A_FLAC", private)
+        }
+        _ => return Err(invalid(format!("unsupported audio codec {}", String::from_utf8_lossy(&kind)))),
+    };
+    Ok(TrackInfo {
+        codec_id,
+        codec_private,
+        kind: TrackKind::Audio {
+            channels: read_u16(entry, 16)?,
+            sample_rate: read_u32(entry, 24)? >> 16,
+            bit_depth: read_u16(entry, 18)?,
+        },
+    })
+}
+
+// mdhd 中的语言，每个字母占 5 位，加上 0x60 即为小写字母
+fn subtitle_language(track: &Track) -> String {
+    let code = u16::from_be_bytes(track.language);
+    let language = [code >> 10, code >> 5, code]
+        .iter()
+        .map(|c| ((c & 0x1f) as u8 + 0x60) as char)
+        .collect::<String>();
+    if language.chars().all(|c| c.is_ascii_lowercase()) {
+        language
+    } else {
+        "und".to_string()
+    }
+}
+
+fn build_tracks(tracks: &[Track]) -> io::Result<Vec<u8>> {
+    let mut entries = Vec::new();
+    for (i, track) in tracks.iter().enumerate() {
+        let info = track_info(track)?;
+        let mut entry = uint_element(TRACK_NUMBER, i as u64 + 1);
+        entry.extend(uint_element(TRACK_UID, i as u64 + 1));
+        entry.extend(uint_element(FLAG_LACING, 0));
+        let language = match &info.kind {
+            TrackKind::Subtitle { language } => language.as_str(),
+            _ => "und",
+        };
+        entry.extend(string_element(LANGUAGE, language));
+        entry.extend(string_element(CODEC_ID, info.codec_id));
+        if !info.codec_private.is_empty() {
+            entry.extend(element(CODEC_PRIVATE, &info.codec_private));
+        }
+        match info.kind {
+            TrackKind::Video { width, height } => {
+                entry.extend(uint_element(TRACK_TYPE, 1));
+                let mut video = uint_element(PIXEL_WIDTH, width as u64);
+                video.extend(uint_element(PIXEL_HEIGHT, height as u64));
+                entry.extend(element(VIDEO, &video));
+            }
+            TrackKind::Audio { channels, sample_rate, bit_depth } => {
+                entry.extend(uint_element(TRACK_TYPE, 2));
+                let mut audio = float_element(SAMPLING_FREQUENCY, sample_rate as f64);
+                audio.extend(uint_element(CHANNELS, channels as u64));
+                if bit_depth != 0 {
+                    audio.extend(uint_element(BIT_DEPTH, bit_depth as u64));
+                }
+                entry.extend(element(AUDIO, &audio));
+            }
+            TrackKind::Subtitle { .. } => {
+                entry.extend(uint_element(TRACK_TYPE, 17));
+            }
+        }
+        entries.extend(element(TRACK_ENTRY, &entry));
+    }
+
+    Ok(element(TRACKS, &entries))
+}
+
+// SeekPosition 固定 8 字节，写完 Cues 后回填
+fn seek_entry(id: u32, position: u64) -> Vec<u8> {
+    let mut seek_id = Vec::new();
+    write_id(&mut seek_id, id);
+    let mut seek = element(SEEK_ID, &seek_id);
+    seek.extend(element(SEEK_POSITION, &position.to_be_bytes()));
+    element(SEEK, &seek)
+}
+
+fn build_seek_head(entries: &[(u32, u64)]) -> Vec<u8> {
+    let seeks = entries
+        .iter()
+        .flat_map(|&(id, position)| seek_entry(id, position))
+        .collect::<Vec<_>>();
+    element(SEEK_HEAD, &seeks)
+}
+
+// 作用于整个文件（TargetTypeValue 50）的标签
+fn build_tags(metadata: &Metadata) -> Option<Vec<u8>> {
+    let mut tag = element(TARGETS, &uint_element(TARGET_TYPE_VALUE, 50));
+    let mut empty = true;
+    for (name, value) in [
+        ("TITLE", &metadata.title),
+        ("ARTIST", &metadata.artist),
+        ("DATE_RELEASED", &metadata.date),
+        ("DESCRIPTION", &metadata.description),
+    ] {
+        if !value.is_empty() {
+            let mut simple_tag = string_element(TAG_NAME, name);
+            simple_tag.extend(string_element(TAG_STRING, value));
+            tag.extend(element(SIMPLE_TAG, &simple_tag));
+            empty = false;
+        }
+    }
+    if empty {
+        return None;
+    }
+    Some(element(TAGS, &element(TAG, &tag)))
+}
+
+// 封面作为附件保存，播放器按 cover 文件名识别
+fn build_attachments(cover: &[u8]) -> Option<Vec<u8>> {
+    if cover.is_empty() {
+        return None;
+    }
+    let Some((_, mime_type)) = image_type(cover) else {
+        eprintln!("Unsupported cover image format, skipped");
+        return None;
+    };
+    let extension = if mime_type == "image/png" { "png" } else { "jpg" };
+    let mut file = string_element(FILE_NAME, &format!("cover.{}", extension));
+    file.extend(string_element(FILE_MIME_TYPE, mime_type));
+    file.extend(element(FILE_DATA, cover));
+    file.extend(uint_element(FILE_UID, 1));
+    Some(element(ATTACHMENTS, &element(ATTACHED_FILE, &file)))
+}
+
+// 写入期间记录当前位置，方便计算 Cluster 的偏移
+struct Output {
+    writer: BufWriter<File>,
+    position: u64,
+}
+
+impl Output {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.writer.write_all(data)?;
+        self.position += data.len() as u64;
+        Ok(())
+    }
+
+    // 写入长度待定的元素头，返回长度字段的位置
+    fn begin(&mut self, id: u32) -> io::Result<u64> {
+        let mut header = Vec::new();
+        write_id(&mut header, id);
+        self.write(&header)?;
+        let position = self.position;
+        self.write(&[0u8; UNKNOWN_SIZE_LENGTH])?;
+        Ok(position)
+    }
+
+    fn end(&mut self, size_position: u64) -> io::Result<()> {
+        let size = self.position - size_position - UNKNOWN_SIZE_LENGTH as u64;
+        let mut encoded = Vec::new();
+        write_size_with_length(&mut encoded, size, UNKNOWN_SIZE_LENGTH);
+        self.patch(size_position, &encoded)
+    }
+
+    fn patch(&mut self, position: u64, data: &[u8]) -> io::Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_mut();
+        file.seek(SeekFrom::Start(position))?;
+        file.write_all(data)?;
+        file.seek(SeekFrom::Start(self.position))?;
+        Ok(())
+    }
+}
+
+// 将轨道写为 Matroska 文件，时间单位为毫秒
+pub fn write_mkv(tracks: &[Track], output: &str, metadata: &Metadata, cover: &[u8]) -> io::Result<()> {
+    if tracks.is_empty() {
+        return Err(invalid("no track to write"));
+    }
+
+    // 显示时间可能因为 B 帧偏移为负数，整体平移到从 0 开始
+    let mut shift = 0f64;
+    for track in tracks {
+        let mut dts = 0i64;
+        for sample in &track.samples {
+            let pts = (dts + sample.cts_offset as i64) as f64 / track.timescale as f64;
+            shift = shift.max(-pts);
+            dts += sample.duration as i64;
+        }
+    }
+    let duration = tracks
+        .iter()
+        .map(|track| track.duration() as f64 * 1000.0 / track.timescale as f64)
+        .fold(0f64, f64::max);
+
+    let mut header = uint_element(EBML_VERSION, 1);
+    header.extend(uint_element(EBML_READ_VERSION, 1));
+    header.extend(uint_element(EBML_MAX_ID_LENGTH, 4));
+    header.extend(uint_element(EBML_MAX_SIZE_LENGTH, 8));
+    header.extend(string_element(DOC_TYPE, "matroska"));
+    header.extend(uint_element(DOC_TYPE_VERSION, 4));
+    header.extend(uint_element(DOC_TYPE_READ_VERSION, 2));
+
+    let mut info = uint_element(TIMESTAMP_SCALE, 1_000_000);
+    info.extend(float_element(DURATION, duration));
+    info.extend(string_element(MUXING_APP, "bilibili-downloader"));
+    info.extend(string_element(WRITING_APP, "bilibili-downloader"));
+    if !metadata.title.is_empty() {
+        info.extend(string_element(TITLE, &metadata.title));
+    }
+    let info = element(INFO, &info);
+    let track_entries = build_tracks(tracks)?;
+
+    // 写在 Cluster 之前的元素，Cues 的位置最后才知道
+    let mut heads = vec![(INFO, info), (TRACKS, track_entries)];
+    if let Some(tags) = build_tags(metadata) {
+        heads.push((TAGS, tags));
+    }
+    if let Some(attachments) = build_attachments(cover) {
+        heads.push((ATTACHMENTS, attachments));
+    }
+
+    let mut out = Output {
+        writer: BufWriter::new(File::create(output)?),
+        position: 0,
+    };
+    out.write(&element(EBML, &header))?;
+    let segment_size = out.begin(SEGMENT)?;
+    let segment_start = out.position;
+
+    // 各元素相对 Segment 数据开头的位置
+    let mut seeks = heads.iter().map(|(id, _)| (*id, 0)).collect::<Vec<_>>();
+    seeks.push((CUES, 0));
+    let mut position = build_seek_head(&seeks).len() as u64;
+    for (seek, (_, data)) in seeks.iter_mut().zip(&heads) {
+        seek.1 = position;
+        position += data.len() as u64;
+    }
+    let seek_head_start = out.position;
+    out.write(&build_seek_head(&seeks))?;
+    for (_, data) in &heads {
+        out.write(data)?;
+    }
+
+    let cue_track = tracks.iter().position(|track| track.is_video()).unwrap_or(0);
+    let subtitles = tracks.iter().map(|track| &track.handler == b"sbtl").collect::<Vec<_>>();
+    let mut cues = Vec::new();
+    let mut inputs = tracks.iter().map(|track| File::open(&track.path)).collect::<io::Result<Vec<_>>>()?;
+    let mut dts = vec![0i64; tracks.len()];
+    let mut cluster: Option<(u64, i64)> = None;
+    let mut buffer = Vec::new();
+
+    for chunk in interleave(tracks) {
+        let track = &tracks[chunk.track];
+        for sample in &track.samples[chunk.first..chunk.first + chunk.count] {
+            let pts = (dts[chunk.track] + sample.cts_offset as i64) as f64 / track.timescale as f64;
+            let timestamp = ((pts + shift) * 1000.0).round() as i64;
+            dts[chunk.track] += sample.duration as i64;
+
+            // 在视频关键帧处切分 Cluster，同时保证相对时间不溢出
+            let starts_cluster = match cluster {
+                None => true,
+                Some((_, start)) => {
+                    let elapsed = timestamp - start;
+                    let keyframe = chunk.track == cue_track && sample.sync;
+                    (keyframe && elapsed >= CLUSTER_DURATION) || !(-MAX_CLUSTER_DURATION..=MAX_CLUSTER_DURATION).contains(&elapsed)
+                }
+            };
+            if starts_cluster {
+                if let Some((size_position, _)) = cluster {
+                    out.end(size_position)?;
+                }
+                let position = out.position - segment_start;
+                let size_position = out.begin(CLUSTER)?;
+                out.write(&uint_element(TIMESTAMP, timestamp.max(0) as u64))?;
+                cluster = Some((size_position, timestamp.max(0)));
+
+                if chunk.track == cue_track && sample.sync {
+                    let mut positions = uint_element(CUE_TRACK, cue_track as u64 + 1);
+                    positions.extend(uint_element(CUE_CLUSTER_POSITION, position));
+                    let mut point = uint_element(CUE_TIME, timestamp.max(0) as u64);
+                    point.extend(element(CUE_TRACK_POSITIONS, &positions));
+                    cues.extend(element(CUE_POINT, &point));
+                }
+            }
+
+            let (_, start) = cluster.unwrap();
+            buffer.resize(sample.size as usize, 0);
+            let input = &mut inputs[chunk.track];
+            input.seek(SeekFrom::Start(sample.offset))?;
+            input.read_exact(&mut buffer)?;
+
+            let mut block = Vec::with_capacity(buffer.len() + 4);
+            write_size(&mut block, chunk.track as u64 + 1);
+            block.extend_from_slice(&((timestamp - start) as i16).to_be_bytes());
+            if subtitles[chunk.track] {
+                // 字幕需要显示时长，使用 BlockGroup，句子之间的空样本不写入
+                let text = buffer.get(2..).unwrap_or_default();
+                if text.is_empty() {
+                    continue;
+                }
+                block.push(0);
+                block.extend_from_slice(text);
+                let duration = (sample.duration as f64 * 1000.0 / track.timescale as f64).round() as u64;
+                let mut group = element(BLOCK, &block);
+                group.extend(uint_element(BLOCK_DURATION, duration));
+                out.write(&element(BLOCK_GROUP, &group))?;
+                continue;
+            }
+            block.push(if sample.sync { 0x80 } else { 0 });
+            let mut header = Vec::new();
+            write_id(&mut header, SIMPLE_BLOCK);
+            write_size(&mut header, (block.len() + buffer.len()) as u64);
+            out.write(&header)?;
+            out.write(&block)?;
+            out.write(&buffer)?;
+        }
+    }
+    if let Some((size_position, _)) = cluster {
+        out.end(size_position)?;
+    }
+
+    let cues_position = out.position - segment_start;
+    out.write(&element(CUES, &cues))?;
+    out.end(segment_size)?;
+    *seeks.last_mut().unwrap() = (CUES, cues_position);
+    out.patch(seek_head_start, &build_seek_head(&seeks))?;
+    out.writer.flush()?;
+
+    Ok(())
+}
+
+// 合并 DASH 的音视频流为一个 MKV 文件，字幕写为 S_TEXT/UTF8 轨道
+pub fn remux(inputs: &[&str], output: &str, metadata: &Metadata, cover: &[u8], subtitles: &[SubtitleTrack]) -> Result<(), String> {
+    let mut tracks = inputs
+        .iter()
+        .map(|input| read_track(input).map_err(|e| format!("failed to read {}: {}", input, e)))
+        .collect::<Result<Vec<_>, String>>()?;
+    let texts = text_tracks(subtitles, output)?;
+    tracks.extend(texts.iter().cloned());
+    let result = write_mkv(&tracks, output, metadata, cover).map_err(|e| format!("failed to write {}: {}", output, e));
+    remove_text_tracks(&texts);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::mp4::tests::{init_segment, media_segment, temp_path, CODEC_CONFIG};
+
+    // 变长整数的值和长度，ID 保留长度标记位
+    fn read_vint(data: &[u8], pos: usize, keep_marker: bool) -> (u64, usize) {
+        let length = data[pos].leading_zeros() as usize + 1;
+        let mut value = data[pos..pos + length].iter().fold(0u64, |value, b| value << 8 | *b as u64);
+        if !keep_marker {
+            value &= (1u64 << (7 * length)) - 1;
+        }
+        (value, length)
+    }
+
+    // 一段数据中的元素 ID、内容和元素开头的位置
+    fn elements(data: &[u8]) -> Vec<(u32, &[u8], usize)> {
+        let mut elements = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let (id, id_length) = read_vint(data, pos, true);
+            let (size, size_length) = read_vint(data, pos + id_length, false);
+            let start = pos + id_length + size_length;
+            elements.push((id as u32, &data[start..start + size as usize], pos));
+            pos = start + size as usize;
+        }
+        elements
+    }
+
+    fn find(data: &[u8], id: u32) -> &[u8] {
+        elements(data).into_iter().find(|(i, _, _)| *i == id).map(|(_, payload, _)| payload).unwrap()
+    }
+
+    fn read_uint(data: &[u8]) -> u64 {
+        data.iter().fold(0u64, |value, b| value << 8 | *b as u64)
+    }
+
+    #[test]
+    fn writes_matroska_with_cues() {
+        // 两个分片各 1.2 秒，第二个分片的关键帧开始新的 Cluster
+        let frames: Vec<Vec<u8>> = (0..30u8).map(|i| vec![i; 16]).collect();
+        let frames: Vec<&[u8]> = frames.iter().map(|frame| frame.as_slice()).collect();
+        let input = temp_path("mkv.m4s");
+        let output = temp_path("mkv.mkv");
+        let mut data = init_segment(b"avc1");
+        data.extend(media_segment(&frames));
+        data.extend(media_segment(&frames));
+        fs::write(&input, data).unwrap();
+
+        let track = read_track(&input).unwrap();
+        let metadata = Metadata { title: "title".to_string(), ..Default::default() };
+        write_mkv(&[track], &output, &metadata, &[]).unwrap();
+        let data = fs::read(&output).unwrap();
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        let top = elements(&data);
+        assert_eq!(top.iter().map(|(id, _, _)| *id).collect::<Vec<_>>(), [EBML, SEGMENT]);
+        assert_eq!(find(top[0].1, DOC_TYPE), b"matroska");
+
+        // Segment 的长度一直到文件末尾
+        let (_, segment, segment_pos) = top[1];
+        let segment_start = data.len() - segment.len();
+        assert_eq!(segment_start, segment_pos + 4 + UNKNOWN_SIZE_LENGTH);
+
+        let children = elements(segment);
+        let clusters: Vec<u64> = children.iter().filter(|(id, _, _)| *id == CLUSTER).map(|(_, _, pos)| *pos as u64).collect();
+        assert_eq!(clusters.len(), 2);
+        let blocks = children.iter()
+            .filter(|(id, _, _)| *id == CLUSTER)
+            .map(|(_, cluster, _)| elements(cluster).iter().filter(|(id, _, _)| *id == SIMPLE_BLOCK).count())
+            .sum::<usize>();
+        assert_eq!(blocks, 60);
+
+        // Cues 中的位置指向 Cluster
+        let cues = find(segment, CUES);
+        let positions: Vec<u64> = elements(cues)
+            .iter()
+            .map(|(_, point, _)| read_uint(find(find(point, CUE_TRACK_POSITIONS), CUE_CLUSTER_POSITION)))
+            .collect();
+        assert_eq!(positions, clusters);
+        for position in positions {
+            let start = segment_start + position as usize;
+            assert_eq!(&data[start..start + 4], &CLUSTER.to_be_bytes());
+        }
+
+        // SeekHead 中的 Cues 位置在写完后回填
+        let seek_head = find(segment, SEEK_HEAD);
+        let cues_seek = elements(seek_head)
+            .into_iter()
+            .map(|(_, seek, _)| (read_uint(find(seek, SEEK_ID)) as u32, read_uint(find(seek, SEEK_POSITION))))
+            .find(|(id, _)| *id == CUES)
+            .unwrap();
+        let start = segment_start + cues_seek.1 as usize;
+        assert_eq!(&data[start..start + 4], &CUES.to_be_bytes());
+
+        let entry = find(find(segment, TRACKS), TRACK_ENTRY);
+        assert_eq!(find(entry, CODEC_ID), b"V_MPEG4/ISO/AVC");
+        assert_eq!(find(entry, CODEC_PRIVATE), CODEC_CONFIG);
+        assert_eq!(find(find(segment, INFO), TITLE), b"title");
+    }
+}
@@ -0,0 +1,970 @@
+use std::fs::File;
+use std::io::{self, copy, BufWriter, Read, Seek, SeekFrom, Write};
+
+use crate::merger::Metadata;
+use crate::subtitle::{remove_text_tracks, text_tracks, SubtitleTrack};
+
+// 每个 chunk 包含的最长时间（秒），音视频按时间交错写入
+const CHUNK_DURATION: f64 = 0.5;
+// 输出文件的时间刻度
+const MOVIE_TIMESCALE: u32 = 1000;
+
+// 样本在输入文件中的位置和时间信息
+#[derive(Debug, Clone)]
+pub struct Sample {
+    pub(crate) offset: u64,
+    pub(crate) size: u32,
+    pub(crate) duration: u32,
+    pub(crate) cts_offset: i32,
+    pub(crate) sync: bool,
+}
+
+// 从 DASH 分片文件（.m4s）中解析出的轨道，保留原始的描述信息
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub(crate) path: String,
+    pub(crate) handler: [u8; 4],
+    pub(crate) timescale: u32,
+    pub(crate) language: [u8; 2],
+    // tkhd 末尾的 layer、音量、矩阵和宽高
+    pub(crate) tkhd_tail: Vec<u8>,
+    // hdlr、vmhd/smhd、dinf 和 stsd 的完整 box
+    pub(crate) hdlr: Vec<u8>,
+    pub(crate) media_header: Vec<u8>,
+    pub(crate) dinf: Vec<u8>,
+    pub(crate) stsd: Vec<u8>,
+    pub(crate) samples: Vec<Sample>,
+}
+
+impl Track {
+    pub fn is_video(&self) -> bool {
+        &self.handler == b"vide"
+    }
+
+    // stsd 中第一个样本描述的类型和内容，例如 avc1、mp4a
+    pub fn sample_entry(&self) -> io::Result<([u8; 4], &[u8])> {
+        let (_, stsd, _) = *children(&self.stsd)?.first().ok_or_else(|| invalid("empty stsd box"))?;
+        let (kind, entry, _) = *children(stsd.get(8..).unwrap_or_default())?
+            .first()
+            .ok_or_else(|| invalid("stsd box has no sample entry"))?;
+        Ok((kind, entry))
+    }
+
+    // 以轨道时间刻度计的总时长
+    pub fn duration(&self) -> u64 {
+        self.samples.iter().map(|sample| sample.duration as u64).sum()
+    }
+}
+
+// trex 和 tfhd 中的默认值
+#[derive(Debug, Clone, Copy, Default)]
+struct SampleDefaults {
+    duration: u32,
+    size: u32,
+    flags: u32,
+}
+
+// 连续写入同一轨道的若干个样本
+pub(crate) struct Chunk {
+    pub(crate) track: usize,
+    pub(crate) first: usize,
+    pub(crate) count: usize,
+}
+
+pub(crate) fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+pub(crate) fn read_u16(data: &[u8], pos: usize) -> io::Result<u16> {
+    data.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| invalid("unexpected end of box"))
+}
+
+pub(crate) fn read_u32(data: &[u8], pos: usize) -> io::Result<u32> {
+    data.get(pos..pos + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| invalid("unexpected end of box"))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> io::Result<u64> {
+    Ok(((read_u32(data, pos)? as u64) << 32) | read_u32(data, pos + 4)? as u64)
+}
+
+// box 的类型、内容和包括头部的完整数据
+pub(crate) type BoxEntry<'a> = ([u8; 4], &'a [u8], &'a [u8]);
+
+// 遍历一段数据中的 box
+pub(crate) fn children(data: &[u8]) -> io::Result<Vec<BoxEntry<'_>>> {
+    let mut boxes = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let mut size = read_u32(data, pos)? as usize;
+        let kind = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+        let mut header = 8;
+        if size == 1 {
+            size = read_u64(data, pos + 8)? as usize;
+            header = 16;
+        } else if size == 0 {
+            size = data.len() - pos;
+        }
+        if size < header || pos + size > data.len() {
+            return Err(invalid(format!("invalid size of box {}", String::from_utf8_lossy(&kind))));
+        }
+        boxes.push((kind, &data[pos + header..pos + size], &data[pos..pos + size]));
+        pos += size;
+    }
+
+    Ok(boxes)
+}
+
+fn find<'a>(data: &'a [u8], kind: &[u8; 4]) -> io::Result<Option<(&'a [u8], &'a [u8])>> {
+    Ok(children(data)?
+        .into_iter()
+        .find(|(k, _, _)| k == kind)
+        .map(|(_, payload, whole)| (payload, whole)))
+}
+
+fn require<'a>(data: &'a [u8], kind: &[u8; 4]) -> io::Result<(&'a [u8], &'a [u8])> {
+    find(data, kind)?.ok_or_else(|| invalid(format!("missing {} box", String::from_utf8_lossy(kind))))
+}
+
+// 读取文件中下一个顶层 box 的类型、头部长度和总长度
+fn read_header(file: &mut File, remain: u64) -> io::Result<([u8; 4], u64, u64)> {
+    let mut buf = [0u8; 8];
+    file.read_exact(&mut buf)?;
+    let mut size = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as u64;
+    let kind = [buf[4], buf[5], buf[6], buf[7]];
+    let mut header = 8;
+    if size == 1 {
+        let mut large = [0u8; 8];
+        file.read_exact(&mut large)?;
+        size = u64::from_be_bytes(large);
+        header = 16;
+    } else if size == 0 {
+        size = remain;
+    }
+    if size < header || size > remain {
+        return Err(invalid(format!("invalid size of box {}", String::from_utf8_lossy(&kind))));
+    }
+
+    Ok((kind, header, size))
+}
+
+fn parse_trak(trak: &[u8]) -> io::Result<(u32, Track)> {
+    let (tkhd, _) = require(trak, b"tkhd")?;
+    let track_id = if tkhd.first() == Some(&1) { read_u32(tkhd, 20)? } else { read_u32(tkhd, 12)? };
+    if tkhd.len() < 60 {
+        return Err(invalid("tkhd box is too short"));
+    }
+
+    let (mdia, _) = require(trak, b"mdia")?;
+    let (mdhd, _) = require(mdia, b"mdhd")?;
+    let (timescale, language_pos) = if mdhd.first() == Some(&1) { (read_u32(mdhd, 20)?, 32) } else { (read_u32(mdhd, 12)?, 20) };
+    let language = read_u16(mdhd, language_pos)?.to_be_bytes();
+    let (hdlr_payload, hdlr) = require(mdia, b"hdlr")?;
+    let handler = hdlr_payload.get(8..12).ok_or_else(|| invalid("hdlr box is too short"))?;
+
+    let (minf, _) = require(mdia, b"minf")?;
+    let media_header = children(minf)?
+        .into_iter()
+        .find(|(k, _, _)| matches!(k, b"vmhd" | b"smhd" | b"sthd" | b"nmhd"))
+        .map(|(_, _, whole)| whole.to_vec())
+        .unwrap_or_default();
+    let dinf = find(minf, b"dinf")?.map(|(_, whole)| whole.to_vec()).unwrap_or_default();
+    let (stbl, _) = require(minf, b"stbl")?;
+    let (_, stsd) = require(stbl, b"stsd")?;
+
+    Ok((track_id, Track {
+        path: String::new(),
+        handler: [handler[0], handler[1], handler[2], handler[3]],
+        timescale,
+        language,
+        tkhd_tail: tkhd[tkhd.len() - 60..].to_vec(),
+        hdlr: hdlr.to_vec(),
+        media_header,
+        dinf,
+        stsd: stsd.to_vec(),
+        samples: Vec::new(),
+    }))
+}
+
+fn parse_trex(moov: &[u8], track_id: u32) -> io::Result<SampleDefaults> {
+    if let Some((mvex, _)) = find(moov, b"mvex")? {
+        for (kind, trex, _) in children(mvex)? {
+            if &kind == b"trex" && read_u32(trex, 4)? == track_id {
+                return Ok(SampleDefaults {
+                    duration: read_u32(trex, 12)?,
+                    size: read_u32(trex, 16)?,
+                    flags: read_u32(trex, 20)?,
+                });
+            }
+        }
+    }
+
+    Ok(SampleDefaults::default())
+}
+
+// 解析 moof 中的 traf，样本的位置由 tfhd 的基准偏移和 trun 的 data_offset 决定
+fn parse_moof(moof: &[u8], moof_start: u64, track_id: u32, trex: SampleDefaults, samples: &mut Vec<Sample>) -> io::Result<()> {
+    for (kind, traf, _) in children(moof)? {
+        if &kind != b"traf" {
+            continue;
+        }
+
+        let (tfhd, _) = require(traf, b"tfhd")?;
+        let tfhd_flags = read_u32(tfhd, 0)? & 0xffffff;
+        if read_u32(tfhd, 4)? != track_id {
+            continue;
+        }
+        let mut pos = 8;
+        let mut base = moof_start;
+        let mut defaults = trex;
+        if tfhd_flags & 0x1 != 0 {
+            base = read_u64(tfhd, pos)?;
+            pos += 8;
+        }
+        if tfhd_flags & 0x2 != 0 {
+            pos += 4;
+        }
+        if tfhd_flags & 0x8 != 0 {
+            defaults.duration = read_u32(tfhd, pos)?;
+            pos += 4;
+        }
+        if tfhd_flags & 0x10 != 0 {
+            defaults.size = read_u32(tfhd, pos)?;
+            pos += 4;
+        }
+        if tfhd_flags & 0x20 != 0 {
+            defaults.flags = read_u32(tfhd, pos)?;
+        }
+
+        // 没有 data_offset 的 trun 紧接着上一个 trun 的数据
+        let mut data = base;
+        for (kind, trun, _) in children(traf)? {
+            if &kind != b"trun" {
+                continue;
+            }
+
+            let version = *trun.first().ok_or_else(|| invalid("empty trun box"))?;
+            let flags = read_u32(trun, 0)? & 0xffffff;
+            let count = read_u32(trun, 4)?;
+            let mut pos = 8;
+            if flags & 0x1 != 0 {
+                data = base.checked_add_signed(read_u32(trun, pos)? as i32 as i64).ok_or_else(|| invalid("invalid trun data offset"))?;
+                pos += 4;
+            }
+            let mut first_flags = None;
+            if flags & 0x4 != 0 {
+                first_flags = Some(read_u32(trun, pos)?);
+                pos += 4;
+            }
+
+            // 每个样本的字段长度固定，先确认 trun 中有 count 个样本的数据
+            let field_count = [0x100, 0x200, 0x400, 0x800].iter().filter(|&&flag| flags & flag != 0).count();
+            if (trun.len() - pos) / 4 < field_count * count as usize {
+                return Err(invalid(format!("trun box is too short for {} samples", count)));
+            }
+
+            for i in 0..count {
+                let mut sample = Sample {
+                    offset: data,
+                    size: defaults.size,
+                    duration: defaults.duration,
+                    cts_offset: 0,
+                    sync: true,
+                };
+                let mut sample_flags = defaults.flags;
+                if flags & 0x100 != 0 {
+                    sample.duration = read_u32(trun, pos)?;
+                    pos += 4;
+                }
+                if flags & 0x200 != 0 {
+                    sample.size = read_u32(trun, pos)?;
+                    pos += 4;
+                }
+                if flags & 0x400 != 0 {
+                    sample_flags = read_u32(trun, pos)?;
+                    pos += 4;
+                }
+                if flags & 0x800 != 0 {
+                    let offset = read_u32(trun, pos)?;
+                    sample.cts_offset = if version == 0 { offset.min(i32::MAX as u32) as i32 } else { offset as i32 };
+                    pos += 4;
+                }
+                if i == 0 {
+                    if let Some(first_flags) = first_flags {
+                        sample_flags = first_flags;
+                    }
+                }
+                // sample_is_non_sync_sample 标志
+                sample.sync = sample_flags & 0x10000 == 0;
+                data += sample.size as u64;
+                samples.push(sample);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// 读取 DASH 分片文件中的第一条轨道，只记录样本的位置，不读取媒体数据
+pub fn read_track(path: &str) -> io::Result<Track> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut track: Option<(u32, Track, SampleDefaults)> = None;
+    let mut samples = Vec::new();
+
+    let mut pos = 0;
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        let (kind, header, size) = read_header(&mut file, len - pos)?;
+        match &kind {
+            b"moov" | b"moof" => {
+                let mut data = vec![0u8; (size - header) as usize];
+                file.read_exact(&mut data)?;
+                if &kind == b"moov" {
+                    let (trak, _) = require(&data, b"trak")?;
+                    let (track_id, parsed) = parse_trak(trak)?;
+                    track = Some((track_id, parsed, parse_trex(&data, track_id)?));
+                } else {
+                    let (track_id, _, trex) = track.as_ref().ok_or_else(|| invalid("moof before moov"))?;
+                    parse_moof(&data, pos, *track_id, *trex, &mut samples)?;
+                }
+            }
+            _ => {}
+        }
+        pos += size;
+    }
+
+    let (_, mut track, _) = track.ok_or_else(|| invalid(format!("{} has no moov box", path)))?;
+    if samples.is_empty() {
+        return Err(invalid(format!("{} has no fragmented samples", path)));
+    }
+    if let Some(sample) = samples.iter().find(|sample| sample.offset + sample.size as u64 > len) {
+        return Err(invalid(format!("sample at {} is outside of {}", sample.offset, path)));
+    }
+    track.path = path.to_string();
+    track.samples = samples;
+
+    Ok(track)
+}
+
+pub(crate) fn make_box(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(payload);
+    out
+}
+
+pub(crate) fn make_full_box(kind: &[u8; 4], version: u8, flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(payload.len() + 4);
+    data.extend_from_slice(&((version as u32) << 24 | (flags & 0xffffff)).to_be_bytes());
+    data.extend_from_slice(payload);
+    make_box(kind, &data)
+}
+
+// 按解码时间交错两条轨道的样本，每个 chunk 不超过 CHUNK_DURATION
+pub(crate) fn interleave(tracks: &[Track]) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    let mut next = vec![0usize; tracks.len()];
+    let mut time = vec![0u64; tracks.len()];
+    loop {
+        let current = (0..tracks.len())
+            .filter(|&t| next[t] < tracks[t].samples.len())
+            .min_by(|&a, &b| {
+                let a = time[a] as f64 / tracks[a].timescale as f64;
+                let b = time[b] as f64 / tracks[b].timescale as f64;
+                a.total_cmp(&b)
+            });
+        let Some(t) = current else {
+            break;
+        };
+
+        let track = &tracks[t];
+        let limit = time[t] + (CHUNK_DURATION * track.timescale as f64) as u64;
+        let first = next[t];
+        while next[t] < track.samples.len() && (next[t] == first || time[t] < limit) {
+            time[t] += track.samples[next[t]].duration as u64;
+            next[t] += 1;
+        }
+        chunks.push(Chunk { track: t, first, count: next[t] - first });
+    }
+
+    chunks
+}
+
+fn build_stbl(track: &Track, chunks: &[(u64, usize)], co64: bool) -> Vec<u8> {
+    let samples = &track.samples;
+    let mut stbl = track.stsd.clone();
+
+    // 解码时间间隔，连续相同的合并为一项
+    let mut runs: Vec<(u32, u32)> = Vec::new();
+    for sample in samples {
+        match runs.last_mut() {
+            Some((count, duration)) if *duration == sample.duration => *count += 1,
+            _ => runs.push((1, sample.duration)),
+        }
+    }
+    let mut stts = (runs.len() as u32).to_be_bytes().to_vec();
+    for (count, duration) in runs {
+        stts.extend_from_slice(&count.to_be_bytes());
+        stts.extend_from_slice(&duration.to_be_bytes());
+    }
+    stbl.extend(make_full_box(b"stts", 0, 0, &stts));
+
+    // 显示时间偏移，存在 B 帧时才需要
+    if samples.iter().any(|sample| sample.cts_offset != 0) {
+        let mut runs: Vec<(u32, i32)> = Vec::new();
+        for sample in samples {
+            match runs.last_mut() {
+                Some((count, offset)) if *offset == sample.cts_offset => *count += 1,
+                _ => runs.push((1, sample.cts_offset)),
+            }
+        }
+        let version = if samples.iter().any(|sample| sample.cts_offset < 0) { 1 } else { 0 };
+        let mut ctts = (runs.len() as u32).to_be_bytes().to_vec();
+        for (count, offset) in runs {
+            ctts.extend_from_slice(&count.to_be_bytes());
+            ctts.extend_from_slice(&offset.to_be_bytes());
+        }
+        stbl.extend(make_full_box(b"ctts", version, 0, &ctts));
+    }
+
+    // 关键帧，全部为关键帧时省略
+    if samples.iter().any(|sample| !sample.sync) {
+        let sync: Vec<u32> = samples
+            .iter()
+            .enumerate()
+            .filter(|(_, sample)| sample.sync)
+            .map(|(i, _)| i as u32 + 1)
+            .collect();
+        let mut stss = (sync.len() as u32).to_be_bytes().to_vec();
+        for index in sync {
+            stss.extend_from_slice(&index.to_be_bytes());
+        }
+        stbl.extend(make_full_box(b"stss", 0, 0, &stss));
+    }
+
+    let mut stsc_runs: Vec<(u32, u32)> = Vec::new();
+    for (i, (_, count)) in chunks.iter().enumerate() {
+        if stsc_runs.last().map(|(_, c)| *c) != Some(*count as u32) {
+            stsc_runs.push((i as u32 + 1, *count as u32));
+        }
+    }
+    let mut stsc = (stsc_runs.len() as u32).to_be_bytes().to_vec();
+    for (first, count) in stsc_runs {
+        stsc.extend_from_slice(&first.to_be_bytes());
+        stsc.extend_from_slice(&count.to_be_bytes());
+        stsc.extend_from_slice(&1u32.to_be_bytes());
+    }
+    stbl.extend(make_full_box(b"stsc", 0, 0, &stsc));
+
+    let mut stsz = Vec::new();
+    let first_size = samples[0].size;
+    if samples.iter().all(|sample| sample.size == first_size) {
+        stsz.extend_from_slice(&first_size.to_be_bytes());
+        stsz.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+    } else {
+        stsz.extend_from_slice(&0u32.to_be_bytes());
+        stsz.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+        for sample in samples {
+            stsz.extend_from_slice(&sample.size.to_be_bytes());
+        }
+    }
+    stbl.extend(make_full_box(b"stsz", 0, 0, &stsz));
+
+    let mut stco = (chunks.len() as u32).to_be_bytes().to_vec();
+    for (offset, _) in chunks {
+        if co64 {
+            stco.extend_from_slice(&offset.to_be_bytes());
+        } else {
+            stco.extend_from_slice(&(*offset as u32).to_be_bytes());
+        }
+    }
+    stbl.extend(make_full_box(if co64 { b"co64" } else { b"stco" }, 0, 0, &stco));
+
+    make_box(b"stbl", &stbl)
+}
+
+// 时长超过 32 位时使用 version 1
+fn push_duration(out: &mut Vec<u8>, duration: u64, version: u8) {
+    if version == 1 {
+        out.extend_from_slice(&duration.to_be_bytes());
+    } else {
+        out.extend_from_slice(&(duration as u32).to_be_bytes());
+    }
+}
+
+fn push_times(out: &mut Vec<u8>, version: u8) {
+    let size = if version == 1 { 16 } else { 8 };
+    out.resize(out.len() + size, 0);
+}
+
+fn build_trak(track: &Track, track_id: u32, chunks: &[(u64, usize)], co64: bool) -> Vec<u8> {
+    let media_duration = track.duration();
+    let movie_duration = media_duration * MOVIE_TIMESCALE as u64 / track.timescale.max(1) as u64;
+
+    // 轨道启用并用于播放
+    let version = if movie_duration > u32::MAX as u64 { 1 } else { 0 };
+    let mut tkhd = Vec::new();
+    push_times(&mut tkhd, version);
+    tkhd.extend_from_slice(&track_id.to_be_bytes());
+    tkhd.extend_from_slice(&[0u8; 4]);
+    push_duration(&mut tkhd, movie_duration, version);
+    tkhd.extend_from_slice(&track.tkhd_tail);
+
+    let version = if media_duration > u32::MAX as u64 { 1 } else { 0 };
+    let mut mdhd = Vec::new();
+    push_times(&mut mdhd, version);
+    mdhd.extend_from_slice(&track.timescale.to_be_bytes());
+    push_duration(&mut mdhd, media_duration, version);
+    mdhd.extend_from_slice(&track.language);
+    mdhd.extend_from_slice(&[0u8; 2]);
+
+    let dinf = if track.dinf.is_empty() {
+        // 媒体数据在同一个文件中
+        let mut dref = 1u32.to_be_bytes().to_vec();
+        dref.extend(make_full_box(b"url ", 0, 1, &[]));
+        make_box(b"dinf", &make_full_box(b"dref", 0, 0, &dref))
+    } else {
+        track.dinf.clone()
+    };
+
+    let mut minf = track.media_header.clone();
+    minf.extend(dinf);
+    minf.extend(build_stbl(track, chunks, co64));
+
+    let mut mdia = make_full_box(b"mdhd", version, 0, &mdhd);
+    mdia.extend_from_slice(&track.hdlr);
+    mdia.extend(make_box(b"minf", &minf));
+
+    let mut trak = make_full_box(b"tkhd", if movie_duration > u32::MAX as u64 { 1 } else { 0 }, 0x3, &tkhd);
+    trak.extend(make_box(b"mdia", &mdia));
+    make_box(b"trak", &trak)
+}
+
+fn build_moov(tracks: &[Track], chunk_offsets: &[Vec<(u64, usize)>], base: u64, co64: bool, extra: &[u8]) -> Vec<u8> {
+    let duration = tracks
+        .iter()
+        .map(|track| track.duration() * MOVIE_TIMESCALE as u64 / track.timescale.max(1) as u64)
+        .max()
+        .unwrap_or(0);
+    let version = if duration > u32::MAX as u64 { 1 } else { 0 };
+
+    let mut mvhd = Vec::new();
+    push_times(&mut mvhd, version);
+    mvhd.extend_from_slice(&MOVIE_TIMESCALE.to_be_bytes());
+    push_duration(&mut mvhd, duration, version);
+    // 播放速率 1.0，音量 1.0
+    mvhd.extend_from_slice(&0x00010000u32.to_be_bytes());
+    mvhd.extend_from_slice(&0x0100u16.to_be_bytes());
+    mvhd.extend_from_slice(&[0u8; 10]);
+    for value in [0x00010000u32, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000] {
+        mvhd.extend_from_slice(&value.to_be_bytes());
+    }
+    mvhd.extend_from_slice(&[0u8; 24]);
+    mvhd.extend_from_slice(&(tracks.len() as u32 + 1).to_be_bytes());
+
+    let mut moov = make_full_box(b"mvhd", version, 0, &mvhd);
+    for (i, track) in tracks.iter().enumerate() {
+        let chunks: Vec<(u64, usize)> = chunk_offsets[i].iter().map(|(offset, count)| (base + offset, *count)).collect();
+        moov.extend(build_trak(track, i as u32 + 1, &chunks, co64));
+    }
+    moov.extend_from_slice(extra);
+    make_box(b"moov", &moov)
+}
+
+// 视频编码对应的兼容品牌，其他编码不添加
+fn codec_brand(track: &Track) -> Option<&'static [u8; 4]> {
+    match &track.sample_entry().ok()?.0 {
+        b"avc1" | b"avc3" => Some(b"avc1"),
+        b"hvc1" | b"hev1" => Some(b"hvc1"),
+        b"av01" => Some(b"av01"),
+        _ => None,
+    }
+}
+
+// 只有音频时使用 M4A 的品牌
+fn build_ftyp(tracks: &[Track]) -> Vec<u8> {
+    let mut ftyp = Vec::new();
+    let Some(video) = tracks.iter().find(|track| track.is_video()) else {
+        ftyp.extend_from_slice(b"M4A ");
+        ftyp.extend_from_slice(&0u32.to_be_bytes());
+        ftyp.extend_from_slice(b"M4A mp42isom");
+        return make_box(b"ftyp", &ftyp);
+    };
+    ftyp.extend_from_slice(b"isom");
+    ftyp.extend_from_slice(&512u32.to_be_bytes());
+    ftyp.extend_from_slice(b"isomiso2");
+    if let Some(brand) = codec_brand(video) {
+        ftyp.extend_from_slice(brand);
+    }
+    ftyp.extend_from_slice(b"mp41");
+    make_box(b"ftyp", &ftyp)
+}
+
+// 将轨道写为 moov 在 mdat 之前的 MP4（faststart），extra 为追加到 moov 中的 box，例如 udta
+pub fn write_mp4(tracks: &[Track], output: &str, extra: &[u8]) -> io::Result<()> {
+    if tracks.is_empty() {
+        return Err(invalid("no track to write"));
+    }
+
+    // 每条轨道的 chunk 相对 mdat 数据开头的偏移
+    let chunks = interleave(tracks);
+    let mut chunk_offsets = vec![Vec::new(); tracks.len()];
+    let mut data_len = 0u64;
+    for chunk in &chunks {
+        chunk_offsets[chunk.track].push((data_len, chunk.count));
+        data_len += tracks[chunk.track].samples[chunk.first..chunk.first + chunk.count]
+            .iter()
+            .map(|sample| sample.size as u64)
+            .sum::<u64>();
+    }
+
+    // moov 的长度与偏移的取值无关，先计算长度再填入实际偏移
+    let ftyp = build_ftyp(tracks);
+    let mdat_header = if data_len + 8 > u32::MAX as u64 { 16 } else { 8 };
+    let mut co64 = false;
+    let mut moov = build_moov(tracks, &chunk_offsets, 0, co64, extra);
+    if (ftyp.len() + moov.len()) as u64 + mdat_header + data_len > u32::MAX as u64 {
+        co64 = true;
+        moov = build_moov(tracks, &chunk_offsets, 0, co64, extra);
+    }
+    let base = (ftyp.len() + moov.len()) as u64 + mdat_header;
+    let moov = build_moov(tracks, &chunk_offsets, base, co64, extra);
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    writer.write_all(&ftyp)?;
+    writer.write_all(&moov)?;
+    if mdat_header == 16 {
+        writer.write_all(&1u32.to_be_bytes())?;
+        writer.write_all(b"mdat")?;
+        writer.write_all(&(data_len + 16).to_be_bytes())?;
+    } else {
+        writer.write_all(&(data_len as u32 + 8).to_be_bytes())?;
+        writer.write_all(b"mdat")?;
+    }
+
+    // 输入文件中连续的样本一次复制
+    let mut inputs = tracks.iter().map(|track| File::open(&track.path)).collect::<io::Result<Vec<_>>>()?;
+    for chunk in &chunks {
+        let samples = &tracks[chunk.track].samples[chunk.first..chunk.first + chunk.count];
+        let input = &mut inputs[chunk.track];
+        let mut i = 0;
+        while i < samples.len() {
+            let start = samples[i].offset;
+            let mut end = start + samples[i].size as u64;
+            i += 1;
+            while i < samples.len() && samples[i].offset == end {
+                end += samples[i].size as u64;
+                i += 1;
+            }
+            input.seek(SeekFrom::Start(start))?;
+            let copied = copy(&mut Read::by_ref(input).take(end - start), &mut writer)?;
+            if copied != end - start {
+                return Err(invalid(format!("{} ended early", tracks[chunk.track].path)));
+            }
+        }
+    }
+    writer.flush()?;
+
+    Ok(())
+}
+
+// iTunes 风格的标签项，data 的类型 1 为 UTF-8 文本，13 和 14 为 JPEG 和 PNG 图片
+fn build_item(kind: &[u8; 4], data_type: u32, value: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(value.len() + 8);
+    data.extend_from_slice(&data_type.to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(value);
+    make_box(kind, &make_box(b"data", &data))
+}
+
+// 封面图片的类型，不支持的格式返回 None
+pub(crate) fn image_type(image: &[u8]) -> Option<(u32, &'static str)> {
+    if image.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some((13, "image/jpeg"))
+    } else if image.starts_with(b"\x89PNG") {
+        Some((14, "image/png"))
+    } else {
+        None
+    }
+}
+
+// moov 中的 udta/meta/ilst，包括标题、作者、日期、简介和封面
+fn build_udta(metadata: &Metadata, cover: &[u8]) -> Vec<u8> {
+    let mut items = Vec::new();
+    for (kind, value) in [
+        (b"\xA9nam", &metadata.title),
+        (b"\xA9ART", &metadata.artist),
+        (b"\xA9day", &metadata.date),
+        (b"desc", &metadata.description),
+    ] {
+        if !value.is_empty() {
+            items.extend(build_item(kind, 1, value.as_bytes()));
+        }
+    }
+    if !cover.is_empty() {
+        match image_type(cover) {
+            Some((data_type, _)) => items.extend(build_item(b"covr", data_type, cover)),
+            None => eprintln!("Unsupported cover image format, skipped"),
+        }
+    }
+    if items.is_empty() {
+        return Vec::new();
+    }
+
+    let mut hdlr = vec![0u8; 4];
+    hdlr.extend_from_slice(b"mdir");
+    hdlr.extend_from_slice(b"appl");
+    hdlr.extend_from_slice(&[0u8; 9]);
+    let mut meta = make_full_box(b"hdlr", 0, 0, &hdlr);
+    meta.extend(make_box(b"ilst", &items));
+    make_box(b"udta", &make_full_box(b"meta", 0, 0, &meta))
+}
+
+// 合并 DASH 的视频流和音频流为一个 MP4 文件，不需要外部程序，字幕写为 tx3g 轨道
+pub fn remux(inputs: &[&str], output: &str, metadata: &Metadata, cover: &[u8], subtitles: &[SubtitleTrack]) -> Result<(), String> {
+    let mut tracks = inputs
+        .iter()
+        .map(|input| read_track(input).map_err(|e| format!("failed to read {}: {}", input, e)))
+        .collect::<Result<Vec<_>, String>>()?;
+    let texts = text_tracks(subtitles, output)?;
+    tracks.extend(texts.iter().cloned());
+    let result = write_mp4(&tracks, output, &build_udta(metadata, cover)).map_err(|e| format!("failed to write {}: {}", output, e));
+    remove_text_tracks(&texts);
+    result
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::subtitle::Cue;
+
+    const TRACK_ID: u32 = 1;
+    const TIMESCALE: u32 = 1000;
+
+    pub(crate) fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("mp4_test_{}_{}", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // 样本描述中的解码参数，内容不需要是有效的配置
+    pub(crate) const CODEC_CONFIG: &[u8] = b"\x01config";
+
+    // 只包含一个视频轨道的初始化分片，trex 中默认每个样本 40 个时间单位且不是关键帧
+    pub(crate) fn init_segment(codec: &[u8; 4]) -> Vec<u8> {
+        let mut tkhd = vec![0u8; 8];
+        tkhd.extend_from_slice(&TRACK_ID.to_be_bytes());
+        tkhd.extend_from_slice(&[0u8; 8]);
+        tkhd.extend_from_slice(&[0u8; 60]);
+
+        let mut mdhd = vec![0u8; 8];
+        mdhd.extend_from_slice(&TIMESCALE.to_be_bytes());
+        mdhd.extend_from_slice(&[0u8; 4]);
+        mdhd.extend_from_slice(&0x55c4u16.to_be_bytes());
+        mdhd.extend_from_slice(&[0u8; 2]);
+
+        let mut hdlr = vec![0u8; 4];
+        hdlr.extend_from_slice(b"vide");
+        hdlr.extend_from_slice(&[0u8; 13]);
+
+        let config = match codec {
+            b"hvc1" | b"hev1" => b"hvcC",
+            b"av01" => b"av1C",
+            _ => b"avcC",
+        };
+        let mut entry = vec![0u8; 78];
+        entry.extend(make_box(config, CODEC_CONFIG));
+        let mut stsd = 1u32.to_be_bytes().to_vec();
+        stsd.extend(make_box(codec, &entry));
+        let stbl = make_box(b"stbl", &make_full_box(b"stsd", 0, 0, &stsd));
+        let mut minf = make_full_box(b"vmhd", 0, 1, &[0u8; 8]);
+        minf.extend(stbl);
+
+        let mut mdia = make_full_box(b"mdhd", 0, 0, &mdhd);
+        mdia.extend(make_full_box(b"hdlr", 0, 0, &hdlr));
+        mdia.extend(make_box(b"minf", &minf));
+        let mut trak = make_full_box(b"tkhd", 0, 3, &tkhd);
+        trak.extend(make_box(b"mdia", &mdia));
+
+        let mut trex = TRACK_ID.to_be_bytes().to_vec();
+        for value in [1u32, 40, 0, 0x10000] {
+            trex.extend_from_slice(&value.to_be_bytes());
+        }
+
+        let mut moov = make_box(b"trak", &trak);
+        moov.extend(make_box(b"mvex", &make_full_box(b"trex", 0, 0, &trex)));
+        let mut init = make_box(b"ftyp", b"iso6\0\0\0\0iso6dash");
+        init.extend(make_box(b"moov", &moov));
+        init
+    }
+
+    // 一个 moof 和 mdat，trun 带有 data_offset、首个样本标志和每个样本的大小
+    pub(crate) fn media_segment(samples: &[&[u8]]) -> Vec<u8> {
+        let tfhd = make_full_box(b"tfhd", 0, 0x20000, &TRACK_ID.to_be_bytes());
+        let build_moof = |data_offset: u32| {
+            let mut trun = (samples.len() as u32).to_be_bytes().to_vec();
+            trun.extend_from_slice(&data_offset.to_be_bytes());
+            trun.extend_from_slice(&0u32.to_be_bytes());
+            for sample in samples {
+                trun.extend_from_slice(&(sample.len() as u32).to_be_bytes());
+            }
+            let mut traf = tfhd.clone();
+            traf.extend(make_full_box(b"trun", 0, 0x1 | 0x4 | 0x200, &trun));
+            let mut moof = make_full_box(b"mfhd", 0, 0, &1u32.to_be_bytes());
+            moof.extend(make_box(b"traf", &traf));
+            make_box(b"moof", &moof)
+        };
+
+        // data_offset 从 moof 开头算起，指向 mdat 的数据
+        let moof_len = build_moof(0).len() as u32;
+        let mut segment = build_moof(moof_len + 8);
+        segment.extend(make_box(b"mdat", &samples.concat()));
+        segment
+    }
+
+    fn write_fixture(name: &str, codec: &[u8; 4], samples: &[&[u8]]) -> String {
+        let path = temp_path(name);
+        let mut data = init_segment(codec);
+        data.extend(media_segment(samples));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_samples_from_moof_and_trun() {
+        let samples: [&[u8]; 3] = [b"key", b"delta", b"dd"];
+        let path = write_fixture("read.m4s", b"avc1", &samples);
+        let track = read_track(&path).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert!(track.is_video());
+        assert_eq!(track.timescale, TIMESCALE);
+        assert_eq!(track.samples.len(), 3);
+        for (sample, expected) in track.samples.iter().zip(samples) {
+            let start = sample.offset as usize;
+            assert_eq!(&data[start..start + sample.size as usize], expected);
+            assert_eq!(sample.duration, 40);
+        }
+        // 首个样本的标志覆盖 trex 中的非关键帧标志
+        assert_eq!(track.samples.iter().map(|sample| sample.sync).collect::<Vec<_>>(), [true, false, false]);
+    }
+
+    #[test]
+    fn rejects_truncated_trun() {
+        let empty = make_box(b"traf", &[make_full_box(b"tfhd", 0, 0, &TRACK_ID.to_be_bytes()), make_box(b"trun", &[])].concat());
+        assert!(parse_moof(&empty, 0, TRACK_ID, SampleDefaults::default(), &mut Vec::new()).is_err());
+
+        // 声明了 100 个样本，只有一个样本的大小
+        let mut trun = 100u32.to_be_bytes().to_vec();
+        trun.extend_from_slice(&5u32.to_be_bytes());
+        let short = make_box(b"traf", &[make_full_box(b"tfhd", 0, 0, &TRACK_ID.to_be_bytes()), make_full_box(b"trun", 0, 0x200, &trun)].concat());
+        assert!(parse_moof(&short, 0, TRACK_ID, SampleDefaults::default(), &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn writes_faststart_mp4() {
+        let samples: [&[u8]; 3] = [b"key", b"delta", b"dd"];
+        let input = write_fixture("faststart.m4s", b"avc1", &samples);
+        let output = temp_path("faststart.mp4");
+        let track = read_track(&input).unwrap();
+        write_mp4(&[track], &output, &[]).unwrap();
+        let data = fs::read(&output).unwrap();
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        // moov 在 mdat 之前
+        let boxes = children(&data).unwrap();
+        let kinds: Vec<[u8; 4]> = boxes.iter().map(|(kind, _, _)| *kind).collect();
+        assert_eq!(kinds, [*b"ftyp", *b"moov", *b"mdat"]);
+        let (_, mdat, _) = boxes[2];
+        assert_eq!(mdat, samples.concat().as_slice());
+
+        let (trak, _) = require(boxes[1].1, b"trak").unwrap();
+        let (mdia, _) = require(trak, b"mdia").unwrap();
+        let (minf, _) = require(mdia, b"minf").unwrap();
+        let (stbl, _) = require(minf, b"stbl").unwrap();
+
+        // 所有样本在同一个 chunk 中，偏移指向 mdat 的数据
+        let (stco, _) = require(stbl, b"stco").unwrap();
+        assert_eq!(read_u32(stco, 4).unwrap(), 1);
+        let offset = read_u32(stco, 8).unwrap() as usize;
+        assert_eq!(&data[offset..offset + 3], b"key");
+
+        let (stsz, _) = require(stbl, b"stsz").unwrap();
+        assert_eq!(read_u32(stsz, 8).unwrap(), 3);
+        let (stss, _) = require(stbl, b"stss").unwrap();
+        assert_eq!((read_u32(stss, 4).unwrap(), read_u32(stss, 8).unwrap()), (1, 1));
+        let (stts, _) = require(stbl, b"stts").unwrap();
+        assert_eq!((read_u32(stts, 8).unwrap(), read_u32(stts, 12).unwrap()), (3, 40));
+    }
+
+    #[test]
+    fn writes_text_track() {
+        let input = write_fixture("text.m4s", b"avc1", &[b"key", b"delta"]);
+        let output = temp_path("text.mp4");
+        let cues = vec![
+            Cue { from: 0.5, to: 1.0, content: "第一句".to_string() },
+            Cue { from: 1.5, to: 2.0, content: "second".to_string() },
+        ];
+        let texts = text_tracks(&[SubtitleTrack { lan: "zh-CN".to_string(), srt: String::new(), cues }], &output).unwrap();
+        let mut tracks = vec![read_track(&input).unwrap()];
+        tracks.extend(texts.iter().cloned());
+        write_mp4(&tracks, &output, &[]).unwrap();
+        remove_text_tracks(&texts);
+        let data = fs::read(&output).unwrap();
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        let boxes = children(&data).unwrap();
+        let traks: Vec<&[u8]> = children(boxes[1].1).unwrap()
+            .into_iter()
+            .filter(|(kind, _, _)| kind == b"trak")
+            .map(|(_, trak, _)| trak)
+            .collect();
+        assert_eq!(traks.len(), 2);
+
+        // tkhd 的长度完整，矩阵位于固定的位置
+        let (track_id, text) = parse_trak(traks[1]).unwrap();
+        assert_eq!(track_id, 2);
+        assert_eq!(&text.handler, b"sbtl");
+        assert_eq!(text.tkhd_tail.len(), 60);
+        let (tkhd, _) = require(traks[1], b"tkhd").unwrap();
+        assert_eq!(tkhd.len(), 84);
+        assert_eq!(read_u32(tkhd, 40).unwrap(), 0x00010000);
+        assert_eq!(read_u32(tkhd, 72).unwrap(), 0x40000000);
+        assert_eq!(&text.sample_entry().unwrap().0, b"tx3g");
+
+        // 开头和两句之间的空白各有一个空样本
+        let (mdia, _) = require(traks[1], b"mdia").unwrap();
+        let (minf, _) = require(mdia, b"minf").unwrap();
+        let (stbl, _) = require(minf, b"stbl").unwrap();
+        let (stsz, _) = require(stbl, b"stsz").unwrap();
+        assert_eq!(read_u32(stsz, 8).unwrap(), 4);
+    }
+
+    #[test]
+    fn ftyp_brand_matches_codec() {
+        let brands = |codec: &[u8; 4]| {
+            let path = write_fixture(&format!("brand_{}.m4s", String::from_utf8_lossy(codec)), codec, &[b"frame"]);
+            let track = read_track(&path).unwrap();
+            fs::remove_file(&path).unwrap();
+            build_ftyp(&[track])
+        };
+        let contains = |ftyp: &[u8], brand: &[u8; 4]| ftyp[16..].chunks(4).any(|chunk| chunk == brand);
+
+        assert!(contains(&brands(b"avc1"), b"avc1"));
+        let hevc = brands(b"hev1");
+        assert!(contains(&hevc, b"hvc1") && !contains(&hevc, b"avc1"));
+        let av1 = brands(b"av01");
+        assert!(contains(&av1, b"av01") && !contains(&av1, b"avc1"));
+    }
+}
@@ -28,6 +28,26 @@ pub fn get_path_absolute(dir: &str, args: &[&str]) -> String {
     return path.to_str().unwrap().to_string();
 }
 
+// 音视频流的临时文件，与输出文件同名并加上流的名称
+pub fn get_stream_file_path(file_path: &str, stream: &str) -> String {
+    let path = Path::new(file_path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+    // 旧版本下载到一半的任务使用 _video.mp4 和 _audio.mp4
+    let legacy = path.with_file_name(format!("{}_{}.mp4", stem, stream));
+    let legacy = legacy.to_str().unwrap().to_string();
+    if Path::new(&legacy).exists() || Path::new(&format!("{}.part0", legacy)).exists() {
+        return legacy;
+    }
+
+    path.with_file_name(format!("{}_{}.m4s", stem, stream)).to_str().unwrap().to_string()
+}
+
+// 与输出文件同名的附属文件，例如弹幕和字幕
+pub fn get_sidecar_file_path(file_path: &str, extension: &str) -> String {
+    Path::new(file_path).with_extension(extension).to_str().unwrap().to_string()
+}
+
 pub fn get_unique_file_path(original_path: &str) -> String {
     let original_path = Path::new(original_path);
 
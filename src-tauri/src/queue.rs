@@ -0,0 +1,167 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use rusqlite::{OptionalExtension, params, Result};
+use serde::Deserialize;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::config::CONFIG;
+use crate::download::CONN;
+
+lazy_static! {
+    static ref SEMAPHORE: Arc<Semaphore> = Arc::new(Semaphore::new(CONFIG.lock().unwrap().max_concurrent.max(1)));
+    // 当前 SEMAPHORE 的名额总数
+    static ref PERMITS: Mutex<usize> = Mutex::new(CONFIG.lock().unwrap().max_concurrent.max(1));
+    // 减少任务数时正在被占用、需要在归还时收回的名额数
+    static ref PENDING_FORGETS: Mutex<usize> = Mutex::new(0);
+    // 正在排队等待下载名额的任务
+    static ref WAITING: Mutex<HashSet<i32>> = Mutex::new(HashSet::new());
+    static ref QUEUE_NOTIFY: Notify = Notify::new();
+}
+
+// 调整任务在队列中的位置
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum QueueMove {
+    Up,
+    Down,
+    Top,
+    Bottom,
+}
+
+// 持有期间占用一个下载名额，释放时唤醒排队的任务
+pub struct QueuePermit {
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for QueuePermit {
+    fn drop(&mut self) {
+        // 先归还名额再唤醒，否则被唤醒的任务仍然拿不到名额
+        if let Some(permit) = self.permit.take() {
+            let mut pending = PENDING_FORGETS.lock().unwrap();
+            if *pending > 0 {
+                *pending -= 1;
+                permit.forget();
+            }
+        }
+        QUEUE_NOTIFY.notify_waiters();
+    }
+}
+
+// 离开等待队列时（包括被取消）唤醒其他任务
+struct Waiting(i32);
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        WAITING.lock().unwrap().remove(&self.0);
+        QUEUE_NOTIFY.notify_waiters();
+    }
+}
+
+// 队列中排在最前面的任务，优先级高的在前，同一优先级按队列位置
+async fn next_in_queue(ids: &[i32]) -> Result<Option<i32>> {
+    let ids = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ");
+    let conn = &*CONN.lock().await;
+    conn.query_row(
+        &format!("SELECT id FROM downloads WHERE id IN ({}) ORDER BY priority DESC, queue_position ASC LIMIT 1", ids),
+        [],
+        |row| row.get(0),
+    ).optional()
+}
+
+// 排在所有等待任务之前且有空闲名额时取得名额
+pub async fn try_acquire_turn(id: i32) -> Option<QueuePermit> {
+    let mut candidates: Vec<i32> = WAITING.lock().unwrap().iter().cloned().collect();
+    candidates.push(id);
+    if next_in_queue(&candidates).await.unwrap_or(Some(id)) != Some(id) {
+        return None;
+    }
+
+    SEMAPHORE.clone().try_acquire_owned().ok().map(|permit| QueuePermit {
+        permit: Some(permit),
+    })
+}
+
+// 排队直到轮到该任务，名额释放或队列顺序变化时重新检查
+pub async fn wait_for_turn(id: i32) -> QueuePermit {
+    WAITING.lock().unwrap().insert(id);
+    let _waiting = Waiting(id);
+    loop {
+        let notified = QUEUE_NOTIFY.notified();
+        if let Some(permit) = try_acquire_turn(id).await {
+            return permit;
+        }
+        notified.await;
+    }
+}
+
+// 修改同时下载的任务数，正在下载的任务不受影响，多出的名额在任务结束后收回
+pub fn resize_concurrency(max_concurrent: usize) {
+    let max_concurrent = max_concurrent.max(1);
+    let mut permits = PERMITS.lock().unwrap();
+    let mut pending = PENDING_FORGETS.lock().unwrap();
+    if max_concurrent > *permits {
+        // 先抵消还没有收回的名额，剩下的再加入
+        let added = max_concurrent - *permits;
+        let cancelled = added.min(*pending);
+        *pending -= cancelled;
+        SEMAPHORE.add_permits(added - cancelled);
+    } else if max_concurrent < *permits {
+        let excess = *permits - max_concurrent;
+        let forgotten = SEMAPHORE.forget_permits(excess);
+        *pending += excess - forgotten;
+    }
+    *permits = max_concurrent;
+    QUEUE_NOTIFY.notify_waiters();
+}
+
+// 新任务排在队列末尾
+pub const NEXT_QUEUE_POSITION: &str = "(SELECT IFNULL(MAX(queue_position), 0) + 1 FROM downloads)";
+
+pub async fn move_download(id: i32, direction: QueueMove) -> Result<()> {
+    {
+        let conn = &*CONN.lock().await;
+        let (priority, position): (i32, i64) = conn.query_row(
+            "SELECT priority, queue_position FROM downloads WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+
+        match direction {
+            QueueMove::Top => {
+                conn.execute("UPDATE downloads SET queue_position = (SELECT MIN(queue_position) FROM downloads) - 1 WHERE id = ?1", params![id])?;
+            }
+            QueueMove::Bottom => {
+                conn.execute(&format!("UPDATE downloads SET queue_position = {} WHERE id = ?1", NEXT_QUEUE_POSITION), params![id])?;
+            }
+            QueueMove::Up | QueueMove::Down => {
+                // 和同一优先级中相邻的未完成任务交换位置
+                let sql = match direction {
+                    QueueMove::Up => "SELECT id, queue_position FROM downloads WHERE priority = ?1 AND queue_position < ?2 AND status != 'completed' ORDER BY queue_position DESC LIMIT 1",
+                    _ => "SELECT id, queue_position FROM downloads WHERE priority = ?1 AND queue_position > ?2 AND status != 'completed' ORDER BY queue_position ASC LIMIT 1",
+                };
+                let neighbor: Option<(i32, i64)> = conn
+                    .query_row(sql, params![priority, position], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .optional()?;
+                if let Some((other_id, other_position)) = neighbor {
+                    conn.execute("UPDATE downloads SET queue_position = ?1 WHERE id = ?2", params![other_position, id])?;
+                    conn.execute("UPDATE downloads SET queue_position = ?1 WHERE id = ?2", params![position, other_id])?;
+                }
+            }
+        }
+    }
+    QUEUE_NOTIFY.notify_waiters();
+
+    Ok(())
+}
+
+pub async fn set_priority(id: i32, priority: i32) -> Result<()> {
+    {
+        let conn = &*CONN.lock().await;
+        conn.execute("UPDATE downloads SET priority = ?1 WHERE id = ?2", params![priority, id])?;
+    }
+    QUEUE_NOTIFY.notify_waiters();
+
+    Ok(())
+}
@@ -0,0 +1,254 @@
+use reqwest::Url;
+use serde::Serialize;
+
+use crate::Agent;
+use crate::bvid::{av_to_bv, bv_to_av};
+
+// 需要先请求一次才能得到真实地址的短链接域名
+const SHORT_LINK_HOSTS: [&str; 3] = ["b23.tv", "bili2233.cn", "b23.wtf"];
+
+// 视频可以用 BV 号或者 av 号表示
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoId {
+    Bvid(String),
+    Aid(u64),
+}
+
+impl VideoId {
+    pub fn bvid(&self) -> Result<String, String> {
+        match self {
+            VideoId::Bvid(bvid) => Ok(bvid.clone()),
+            VideoId::Aid(aid) => av_to_bv(*aid),
+        }
+    }
+
+    pub fn aid(&self) -> Result<u64, String> {
+        match self {
+            VideoId::Bvid(bvid) => bv_to_av(bvid),
+            VideoId::Aid(aid) => Ok(*aid),
+        }
+    }
+}
+
+// 用户输入解析出的目标，由对应的接口获取信息
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Target {
+    // 普通视频，page 为链接中 ?p= 指定的分 P，从 1 开始
+    Video { id: VideoId, page: Option<u32> },
+    // 番剧的单集、整季和条目
+    Episode { ep_id: u64 },
+    Season { season_id: u64 },
+    Media { media_id: u64 },
+    // 用户空间、收藏夹、合集和视频列表
+    Space { mid: u64 },
+    Favorite { media_id: u64 },
+    Collection { mid: u64, season_id: u64 },
+    Series { mid: u64, series_id: u64 },
+}
+
+// 解析用户输入的编号或链接，短链接会先请求获取跳转后的地址
+pub async fn resolve(input: &str) -> Result<Target, String> {
+    let input = input.trim();
+    match parse_url(input) {
+        Some(url) if url.host_str().is_some_and(|host| SHORT_LINK_HOSTS.contains(&host)) => {
+            let location = expand_short_link(&url).await?;
+            parse(location.as_str())
+        }
+        _ => parse(input),
+    }
+}
+
+// 不发送请求地解析输入，支持 BV、av、ep、ss 和 md 编号以及各种网页和移动端链接
+pub fn parse(input: &str) -> Result<Target, String> {
+    let input = input.trim();
+    if input.is_empty() {
+        return Err("输入为空".to_string());
+    }
+    if let Some(target) = parse_id(input) {
+        return Ok(target);
+    }
+
+    let url = parse_url(input).ok_or_else(|| format!("无法识别的输入: {}", input))?;
+    let host = url.host_str().unwrap_or_default();
+    if host != "bilibili.com" && !host.ends_with(".bilibili.com") {
+        return Err(format!("不是 bilibili 的链接: {}", input));
+    }
+
+    let segments: Vec<&str> = url.path_segments()
+        .map(|segments| segments.filter(|segment| !segment.is_empty()).collect())
+        .unwrap_or_default();
+    let query = |key: &str| url.query_pairs().find(|(k, _)| k == key).map(|(_, v)| v.into_owned());
+    let page = query("p").and_then(|p| p.parse().ok()).filter(|&p| p > 0);
+
+    let target = if host == "space.bilibili.com" {
+        parse_space(&segments, &query)
+    } else {
+        match segments.as_slice() {
+            // 移动端的分享链接为 /s/video/BV...
+            ["video", id, ..] | ["s", "video", id, ..] => video_id(id).map(|id| Target::Video { id, page }),
+            ["bangumi", "play", id, ..] | ["bangumi", "media", id, ..] => parse_id(id),
+            ["space", mid, ..] => number(mid).map(|mid| Target::Space { mid }),
+            ["medialist", "detail", id, ..] | ["list", id, ..] if id.starts_with("ml") => {
+                number(&id[2..]).map(|media_id| Target::Favorite { media_id })
+            }
+            // 稍后再看和播放列表等页面在参数中带有视频编号
+            _ => query("bvid").and_then(|bvid| video_id(&bvid))
+                .or_else(|| query("aid").and_then(|aid| number(&aid)).map(VideoId::Aid))
+                .map(|id| Target::Video { id, page }),
+        }
+    };
+    target.ok_or_else(|| format!("无法识别的链接: {}", input))
+}
+
+// 空间链接：/{mid}、/{mid}/favlist?fid=、/{mid}/channel/collectiondetail?sid=、/{mid}/lists/{id}?type=
+fn parse_space(segments: &[&str], query: &dyn Fn(&str) -> Option<String>) -> Option<Target> {
+    let mid = number(segments.first()?)?;
+    let sid = || query("sid").and_then(|sid| number(&sid));
+    match &segments[1..] {
+        [] | ["video" | "upload", ..] => Some(Target::Space { mid }),
+        ["favlist", ..] => match query("fid").and_then(|fid| number(&fid)) {
+            Some(media_id) => Some(Target::Favorite { media_id }),
+            None => Some(Target::Space { mid }),
+        },
+        ["channel", "collectiondetail", ..] => sid().map(|season_id| Target::Collection { mid, season_id }),
+        ["channel", "seriesdetail", ..] => sid().map(|series_id| Target::Series { mid, series_id }),
+        ["lists", id, ..] => {
+            let id = number(id)?;
+            match query("type").as_deref() {
+                Some("series") => Some(Target::Series { mid, series_id: id }),
+                _ => Some(Target::Collection { mid, season_id: id }),
+            }
+        }
+        _ => None,
+    }
+}
+
+// 单独的编号，不区分大小写
+fn parse_id(input: &str) -> Option<Target> {
+    if let Some(id) = video_id(input) {
+        return Some(Target::Video { id, page: None });
+    }
+    let prefix = input.get(..2)?.to_ascii_lowercase();
+    let id = number(&input[2..])?;
+    match prefix.as_str() {
+        "ep" => Some(Target::Episode { ep_id: id }),
+        "ss" => Some(Target::Season { season_id: id }),
+        "md" => Some(Target::Media { media_id: id }),
+        _ => None,
+    }
+}
+
+// BV 号为 BV 加上 10 位字母和数字，av 号为 av 加上数字，二者都需要能转换为另一种形式
+fn video_id(input: &str) -> Option<VideoId> {
+    let prefix = input.get(..2)?;
+    let rest = &input[2..];
+    if prefix.eq_ignore_ascii_case("bv") {
+        let bvid = format!("BV{}", rest);
+        return bv_to_av(&bvid).ok().map(|_| VideoId::Bvid(bvid));
+    }
+    if prefix.eq_ignore_ascii_case("av") {
+        return number(rest).filter(|&aid| av_to_bv(aid).is_ok()).map(VideoId::Aid);
+    }
+    None
+}
+
+fn number(input: &str) -> Option<u64> {
+    if input.is_empty() || !input.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    input.parse().ok()
+}
+
+// 没有协议的链接补上 https
+fn parse_url(input: &str) -> Option<Url> {
+    if input.contains("://") {
+        Url::parse(input).ok()
+    } else if input.contains('.') && input.contains('/') {
+        Url::parse(&format!("https://{}", input)).ok()
+    } else {
+        None
+    }
+}
+
+// 请求短链接并跟随跳转，返回最终的地址
+async fn expand_short_link(url: &Url) -> Result<Url, String> {
+    let response = reqwest::Client::new()
+        .get(url.clone())
+        .header("User-Agent", Agent)
+        .send()
+        .await
+        .map_err(|e| format!("request short link {} failed: {}", url, e))?;
+    Ok(response.url().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn video(bvid: &str, page: Option<u32>) -> Target {
+        Target::Video { id: VideoId::Bvid(bvid.to_string()), page }
+    }
+
+    #[test]
+    fn parses_supported_inputs() {
+        let cases = [
+            ("BV17x411w7KC", video("BV17x411w7KC", None)),
+            ("bv17x411w7KC", video("BV17x411w7KC", None)),
+            ("av170001", Target::Video { id: VideoId::Aid(170001), page: None }),
+            ("AV170001", Target::Video { id: VideoId::Aid(170001), page: None }),
+            ("ep12345", Target::Episode { ep_id: 12345 }),
+            ("ss678", Target::Season { season_id: 678 }),
+            ("md28220978", Target::Media { media_id: 28220978 }),
+            ("https://www.bilibili.com/video/BV17x411w7KC", video("BV17x411w7KC", None)),
+            ("https://www.bilibili.com/video/BV17x411w7KC/?p=3&spm_id_from=333", video("BV17x411w7KC", Some(3))),
+            ("www.bilibili.com/video/av170001?p=2", Target::Video { id: VideoId::Aid(170001), page: Some(2) }),
+            ("https://m.bilibili.com/video/BV17x411w7KC?p=0", video("BV17x411w7KC", None)),
+            ("https://www.bilibili.com/s/video/BV17x411w7KC", video("BV17x411w7KC", None)),
+            ("https://www.bilibili.com/list/watchlater?bvid=BV17x411w7KC&p=4", video("BV17x411w7KC", Some(4))),
+            ("https://www.bilibili.com/list/watchlater?aid=170001", Target::Video { id: VideoId::Aid(170001), page: None }),
+            ("https://www.bilibili.com/bangumi/play/ep12345", Target::Episode { ep_id: 12345 }),
+            ("https://www.bilibili.com/bangumi/play/ss678?from=search", Target::Season { season_id: 678 }),
+            ("https://m.bilibili.com/bangumi/media/md28220978", Target::Media { media_id: 28220978 }),
+            ("https://space.bilibili.com/2", Target::Space { mid: 2 }),
+            ("https://space.bilibili.com/2/upload/video", Target::Space { mid: 2 }),
+            ("https://space.bilibili.com/2/favlist?fid=100", Target::Favorite { media_id: 100 }),
+            ("https://space.bilibili.com/2/favlist", Target::Space { mid: 2 }),
+            ("https://www.bilibili.com/medialist/detail/ml100", Target::Favorite { media_id: 100 }),
+            ("https://www.bilibili.com/list/ml100", Target::Favorite { media_id: 100 }),
+            ("https://space.bilibili.com/2/channel/collectiondetail?sid=300", Target::Collection { mid: 2, season_id: 300 }),
+            ("https://space.bilibili.com/2/channel/seriesdetail?sid=400", Target::Series { mid: 2, series_id: 400 }),
+            ("https://space.bilibili.com/2/lists/300?type=season", Target::Collection { mid: 2, season_id: 300 }),
+            ("https://space.bilibili.com/2/lists/400?type=series", Target::Series { mid: 2, series_id: 400 }),
+            ("https://www.bilibili.com/space/2", Target::Space { mid: 2 }),
+            ("  BV17x411w7KC  ", video("BV17x411w7KC", None)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse(input), Ok(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_inputs() {
+        for input in [
+            "",
+            "hello",
+            "BV17x411w7K",
+            "BV17x411w7K0",
+            "av",
+            "av0",
+            "ep",
+            "epabc",
+            "xx123",
+            "https://www.youtube.com/watch?v=abc",
+            "https://evilbilibili.com/video/BV17x411w7KC",
+            "https://www.bilibili.com/",
+            "https://www.bilibili.com/video/",
+            "https://space.bilibili.com/abc",
+            "https://space.bilibili.com/2/channel/collectiondetail",
+        ] {
+            assert!(parse(input).is_err(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use chrono::{Local, NaiveDateTime};
+use tauri::{AppHandle, Emitter};
+
+use crate::config::CONFIG;
+use crate::download::{Download, DownloadStatus, get_downloads_with_status, start_downloading, stop_downloading, update_download_status};
+
+// 检查时间段的间隔
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(30);
+// 任务开始时间的格式
+const START_AT_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+fn now() -> NaiveDateTime {
+    Local::now().naive_local()
+}
+
+// 当前时间在允许的时间段内
+pub fn is_window_open() -> bool {
+    CONFIG.lock().unwrap().schedule.is_open(now())
+}
+
+// 任务设置的开始时间已经到了，没有设置时总是可以开始
+fn is_start_time_reached(download: &Download) -> bool {
+    if download.start_at.is_empty() {
+        return true;
+    }
+    match NaiveDateTime::parse_from_str(&download.start_at, START_AT_FORMAT) {
+        Ok(start_at) => start_at <= now(),
+        Err(err) => {
+            eprintln!("Invalid start time {} of download {}: {}", download.start_at, download.id, err);
+            true
+        }
+    }
+}
+
+pub fn is_download_allowed(download: &Download) -> bool {
+    is_window_open() && is_start_time_reached(download)
+}
+
+// 暂停任务并标记为等待时间段，时间段开始后自动恢复
+pub async fn pause_for_schedule(app: &AppHandle, id: i32) {
+    if stop_downloading(id).await.is_err() {
+        return;
+    }
+    if let Err(e) = update_download_status(id, "scheduled").await {
+        eprintln!("Error updating download status: {}", e);
+        return;
+    }
+    app.emit("status", DownloadStatus {
+        id,
+        status: "scheduled".to_string(),
+        attempt: 0,
+        message: String::new(),
+    }).unwrap();
+}
+
+// 在时间段的边界暂停和恢复任务，时间段内手动开始的任务不受影响
+pub async fn run_scheduler(app: AppHandle) {
+    let mut was_open = is_window_open();
+    let mut ticker = tokio::time::interval(SCHEDULE_INTERVAL);
+    loop {
+        ticker.tick().await;
+
+        let open = is_window_open();
+        if was_open && !open {
+            println!("Download window closed, pausing running downloads");
+            for status in ["downloading", "queued"] {
+                for download in get_downloads_with_status(status).await.unwrap_or_default() {
+                    pause_for_schedule(&app, download.id).await;
+                }
+            }
+        }
+        was_open = open;
+
+        if open {
+            for download in get_downloads_with_status("scheduled").await.unwrap_or_default() {
+                if is_start_time_reached(&download) {
+                    println!("Starting scheduled download {}", download.id);
+                    tokio::spawn(start_downloading(app.clone(), download.id));
+                }
+            }
+        }
+    }
+}
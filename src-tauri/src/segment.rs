@@ -0,0 +1,293 @@
+use std::fmt;
+use std::fs::{metadata, remove_file, rename, File, OpenOptions};
+use std::io::{copy, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderValue, RANGE, REFERER, USER_AGENT};
+use reqwest::{Client, StatusCode};
+use rusqlite::{params, Connection, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::config::RetryPolicy;
+use crate::download::CONN;
+use crate::limiter::{GLOBAL_LIMITER, RateLimiter};
+use crate::Agent;
+
+// 每个分段的最小字节数，文件太小时减少分段数量
+const MIN_SEGMENT_SIZE: i64 = 1024 * 1024;
+// 等待下一块数据的最长时间，超时视为 CDN 无响应
+const READ_TIMEOUT: Duration = Duration::from_secs(15);
+
+// 定义一个结构体来表示音视频流中的一个字节区间
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Segment {
+    pub(crate) download_id: i32,
+    pub(crate) stream: String,
+    pub(crate) idx: i32,
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+    pub(crate) downloaded: i64,
+}
+
+// 分段下载的错误，状态码错误时可以尝试刷新链接，其余错误切换备用 CDN
+#[derive(Debug)]
+pub enum SegmentError {
+    Status(StatusCode),
+    // 连接失败或中途断开
+    Network(String),
+    Timeout,
+    // 持续低于最低速度，参数为当时的速度（字节/秒）
+    Slow(i64),
+    Other(String),
+}
+
+impl fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SegmentError::Status(status) => write!(f, "unexpected response status: {}", status),
+            SegmentError::Network(err) => write!(f, "{}", err),
+            SegmentError::Timeout => write!(f, "timed out waiting for data"),
+            SegmentError::Slow(speed) => write!(f, "download speed dropped to {} B/s", speed),
+            SegmentError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl SegmentError {
+    // 文件读写等错误重试也无法恢复
+    pub fn is_retryable(&self, policy: &RetryPolicy) -> bool {
+        match self {
+            SegmentError::Status(status) => policy.retry_statuses.contains(&status.as_u16()),
+            SegmentError::Network(_) | SegmentError::Timeout | SegmentError::Slow(_) => true,
+            SegmentError::Other(_) => false,
+        }
+    }
+}
+
+impl Segment {
+    pub fn len(&self) -> i64 {
+        self.end - self.start + 1
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.downloaded >= self.len()
+    }
+
+    // 分段临时文件，拼接完成后删除
+    pub fn part_path(&self, file: &str) -> String {
+        format!("{}.part{}", file, self.idx)
+    }
+}
+
+pub fn create_segment_table(conn: &Connection) -> Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS segments (
+            download_id     INTEGER NOT NULL,
+            stream          TEXT NOT NULL CHECK(stream IN ('video', 'audio')),
+            idx             INTEGER NOT NULL,
+            start           INTEGER NOT NULL,
+            end             INTEGER NOT NULL,
+            downloaded      INTEGER NOT NULL,
+            PRIMARY KEY (download_id, stream, idx)
+        )",
+        [],
+    )?;
+
+    Ok(())
+}
+
+// 将 [start, size) 区间平均拆分为若干分段，序号从 first_idx 开始
+pub fn split_segments(download_id: i32, stream: &str, start: i64, size: i64, count: usize, first_idx: i32) -> Vec<Segment> {
+    let length = size - start;
+    if length <= 0 {
+        return Vec::new();
+    }
+
+    let count = (count.max(1) as i64).min((length / MIN_SEGMENT_SIZE).max(1));
+    let step = length / count;
+    let mut segments = Vec::new();
+    for i in 0..count {
+        let begin = start + i * step;
+        let end = if i == count - 1 { size - 1 } else { begin + step - 1 };
+        segments.push(Segment {
+            download_id,
+            stream: stream.to_string(),
+            idx: first_idx + i as i32,
+            start: begin,
+            end,
+            downloaded: 0,
+        });
+    }
+
+    segments
+}
+
+// 为音视频流生成分段，兼容旧版本单连接下载留下的部分文件
+pub fn create_segments(download_id: i32, stream: &str, file: &str, size: i64, count: usize) -> Vec<Segment> {
+    let existed = metadata(file).map(|m| m.len() as i64).unwrap_or(0).min(size);
+    if existed == 0 {
+        return split_segments(download_id, stream, 0, size, count, 0);
+    }
+
+    // 已下载部分作为第 0 段，剩余部分重新拆分
+    let first = Segment {
+        download_id,
+        stream: stream.to_string(),
+        idx: 0,
+        start: 0,
+        end: existed - 1,
+        downloaded: existed,
+    };
+    if let Err(e) = rename(file, first.part_path(file)) {
+        eprintln!("Failed to rename downloaded file: {}", e);
+        return split_segments(download_id, stream, 0, size, count, 0);
+    }
+
+    let mut segments = vec![first];
+    segments.extend(split_segments(download_id, stream, existed, size, count, 1));
+    segments
+}
+
+// 以磁盘上分段文件的实际大小为准，数据库记录可能落后于写入
+pub fn sync_segments_with_disk(segments: &mut [Segment], file: &str) {
+    for segment in segments.iter_mut() {
+        segment.downloaded = metadata(segment.part_path(file))
+            .map(|m| m.len() as i64)
+            .unwrap_or(0)
+            .min(segment.len());
+    }
+}
+
+pub async fn get_segments(download_id: i32, stream: &str) -> Result<Vec<Segment>> {
+    let conn = &*CONN.lock().await;
+    let mut stmt = conn.prepare("SELECT download_id, stream, idx, start, end, downloaded FROM segments WHERE download_id = ?1 AND stream = ?2 ORDER BY idx")?;
+    let segment_iter = stmt.query_map(params![download_id, stream], |row| {
+        Ok(Segment {
+            download_id: row.get(0)?,
+            stream: row.get(1)?,
+            idx: row.get(2)?,
+            start: row.get(3)?,
+            end: row.get(4)?,
+            downloaded: row.get(5)?,
+        })
+    })?;
+
+    let mut segments = Vec::new();
+    for segment in segment_iter {
+        segments.push(segment?);
+    }
+
+    Ok(segments)
+}
+
+pub async fn save_segments(segments: &[Segment]) -> Result<()> {
+    let conn = &*CONN.lock().await;
+    for segment in segments {
+        conn.execute(
+            "INSERT OR REPLACE INTO segments (download_id, stream, idx, start, end, downloaded) VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![segment.download_id, segment.stream, segment.idx, segment.start, segment.end, segment.downloaded],
+        )?;
+    }
+
+    Ok(())
+}
+
+pub async fn delete_segments(download_id: i32, stream: Option<&str>) -> Result<()> {
+    let conn = &*CONN.lock().await;
+    match stream {
+        Some(stream) => conn.execute("DELETE FROM segments WHERE download_id = ?1 AND stream = ?2", params![download_id, stream])?,
+        None => conn.execute("DELETE FROM segments WHERE download_id = ?1", params![download_id])?,
+    };
+
+    Ok(())
+}
+
+// 下载单个分段，从分段文件已有的位置继续
+pub async fn download_segment(client: Client, url: String, referer: String, part_file: String, segment: Segment, counter: Arc<AtomicI64>, limiter: Arc<RateLimiter>) -> Result<(), SegmentError> {
+    let mut downloaded = segment.downloaded;
+    if downloaded >= segment.len() {
+        return Ok(());
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        RANGE,
+        format!("bytes={}-{}", segment.start + downloaded, segment.end)
+            .parse()
+            .unwrap(),
+    );
+    headers.insert(USER_AGENT, HeaderValue::from_static(Agent));
+    headers.insert(REFERER, HeaderValue::from_str(&referer).unwrap());
+
+    let mut res = client
+        .get(&url)
+        .headers(headers)
+        .send()
+        .await
+        .map_err(|e| SegmentError::Network(format!("request segment {} failed: {}", segment.idx, e)))?;
+
+    // 服务器不支持 Range 时会返回整个文件，不能写入分段
+    if res.status() != StatusCode::PARTIAL_CONTENT {
+        return Err(SegmentError::Status(res.status()));
+    }
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&part_file)
+        .map_err(|e| SegmentError::Other(format!("open {} failed: {}", part_file, e)))?;
+    let mut writer = BufWriter::new(file);
+
+    while let Some(chunk) = tokio::time::timeout(READ_TIMEOUT, res.chunk())
+        .await
+        .map_err(|_| SegmentError::Timeout)?
+        .map_err(|e| SegmentError::Network(format!("read segment {} failed: {}", segment.idx, e)))? {
+        let remain = (segment.len() - downloaded) as usize;
+        let chunk = &chunk[..chunk.len().min(remain)];
+        // 任务限速和全局限速同时生效
+        limiter.acquire(chunk.len()).await;
+        GLOBAL_LIMITER.acquire(chunk.len()).await;
+        writer.write_all(chunk).map_err(|e| SegmentError::Other(format!("write {} failed: {}", part_file, e)))?;
+        downloaded += chunk.len() as i64;
+        counter.store(downloaded, Ordering::Relaxed);
+
+        if downloaded >= segment.len() {
+            break;
+        }
+    }
+    writer.flush().map_err(|e| SegmentError::Other(format!("write {} failed: {}", part_file, e)))?;
+
+    if downloaded < segment.len() {
+        return Err(SegmentError::Network(format!("segment {} ended early at {} of {} bytes", segment.idx, downloaded, segment.len())));
+    }
+
+    Ok(())
+}
+
+// 按分段顺序拼接为完整文件，并删除分段文件
+pub fn concat_segments(file: &str, segments: &[Segment]) -> Result<(), String> {
+    let out = File::create(file).map_err(|e| format!("create {} failed: {}", file, e))?;
+    let mut writer = BufWriter::new(out);
+    for segment in segments {
+        let part = File::open(segment.part_path(file)).map_err(|e| format!("open segment {} failed: {}", segment.idx, e))?;
+        copy(&mut part.take(segment.len() as u64), &mut writer).map_err(|e| format!("concat segment {} failed: {}", segment.idx, e))?;
+    }
+    writer.flush().map_err(|e| format!("write {} failed: {}", file, e))?;
+
+    Ok(())
+}
+
+// 删除分段文件，需要在数据库中的分段记录删除之后调用，避免记录指向已删除的文件
+pub fn remove_segment_files(file: &str, segments: &[Segment]) {
+    for segment in segments {
+        let part_path = segment.part_path(file);
+        if Path::new(&part_path).exists() {
+            if let Err(e) = remove_file(&part_path) {
+                eprintln!("Failed to delete segment file: {}", e);
+            }
+        }
+    }
+}
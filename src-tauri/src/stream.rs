@@ -0,0 +1,156 @@
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::config::QualityPreference;
+
+// DASH 视频流的 codecid
+const CODEC_AVC: i64 = 7;
+const CODEC_HEVC: i64 = 12;
+const CODEC_AV1: i64 = 13;
+
+// DASH 中的一个视频流，qn 为清晰度代码，例如 80 表示 1080P
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct VideoStream {
+    #[serde(rename(deserialize = "id"))]
+    pub(crate) qn: i64,
+    #[serde(default)]
+    pub(crate) codecid: i64,
+    #[serde(default)]
+    pub(crate) codecs: String,
+    #[serde(default)]
+    pub(crate) width: i64,
+    #[serde(default)]
+    pub(crate) height: i64,
+    #[serde(default)]
+    pub(crate) frame_rate: String,
+    #[serde(default)]
+    pub(crate) bandwidth: i64,
+    pub(crate) base_url: String,
+    // 备用 CDN 链接
+    #[serde(default, rename(deserialize = "backup_url"), deserialize_with = "null_as_default")]
+    pub(crate) backup_urls: Vec<String>,
+    // 番剧页面中会给出文件大小，其余为 0
+    #[serde(default)]
+    pub(crate) size: i64,
+}
+
+// 接口中没有备用链接时返回 null
+fn null_as_default<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default,
+{
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+impl VideoStream {
+    // 编码名称，对应设置中的 avc、hevc 和 av1
+    pub fn codec_name(&self) -> &'static str {
+        match self.codecid {
+            CODEC_AVC => "avc",
+            CODEC_HEVC => "hevc",
+            CODEC_AV1 => "av1",
+            _ if self.codecs.starts_with("avc") => "avc",
+            _ if self.codecs.starts_with("hev") || self.codecs.starts_with("hvc") => "hevc",
+            _ if self.codecs.starts_with("av01") => "av1",
+            _ => "",
+        }
+    }
+
+    pub fn matches(&self, qn: i64, codecid: i64) -> bool {
+        self.qn == qn && (codecid == 0 || self.codecid == codecid)
+    }
+
+    // 没有给出文件大小时按码率和时长估算，duration 单位为秒
+    pub fn estimated_size(&self, duration: i64) -> i64 {
+        if self.size > 0 {
+            self.size
+        } else {
+            self.bandwidth * duration / 8
+        }
+    }
+}
+
+// DASH 中的一个音频流，id 为音质代码，例如 30280 表示 192K
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct AudioStream {
+    pub(crate) id: i64,
+    #[serde(default)]
+    pub(crate) codecs: String,
+    #[serde(default)]
+    pub(crate) bandwidth: i64,
+    pub(crate) base_url: String,
+    #[serde(default, rename(deserialize = "backup_url"), deserialize_with = "null_as_default")]
+    pub(crate) backup_urls: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct DolbyAudio {
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub(crate) audio: Vec<AudioStream>,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct FlacAudio {
+    #[serde(default)]
+    pub(crate) audio: Option<AudioStream>,
+}
+
+// playurl 接口和番剧页面中的 dash，字段同时有驼峰和下划线两种命名，这里使用下划线的
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Dash {
+    #[serde(default)]
+    pub(crate) duration: i64,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub(crate) video: Vec<VideoStream>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub(crate) audio: Vec<AudioStream>,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub(crate) dolby: DolbyAudio,
+    #[serde(default, deserialize_with = "null_as_default")]
+    pub(crate) flac: FlacAudio,
+}
+
+impl Dash {
+    // 选择音质最好的音频流，有无损音轨时使用 flac，否则在普通音轨和杜比音轨中按码率选择
+    pub fn best_audio(&self) -> Option<(&AudioStream, &'static str)> {
+        if let Some(flac) = &self.flac.audio {
+            return Some((flac, "flac"));
+        }
+        self.audio
+            .iter()
+            .chain(&self.dolby.audio)
+            .max_by_key(|audio| audio.bandwidth)
+            .map(|audio| (audio, "m4a"))
+    }
+}
+
+// 先选择不超过最大分辨率的最高清晰度，同一清晰度按编码优先级和码率选择，返回在 streams 中的序号
+// 设置中没有列出的编码只在没有其他选择时使用
+pub fn pick_video_stream(streams: &[VideoStream], preference: &QualityPreference) -> Option<usize> {
+    let listed = streams
+        .iter()
+        .filter(|stream| preference.codecs.iter().any(|codec| codec == stream.codec_name()))
+        .collect::<Vec<_>>();
+    let mut candidates = if listed.is_empty() { streams.iter().collect() } else { listed };
+
+    if preference.max_height > 0 {
+        if candidates.iter().any(|stream| stream.height <= preference.max_height) {
+            candidates.retain(|stream| stream.height <= preference.max_height);
+        } else {
+            // 都超过限制时使用分辨率最低的
+            let lowest = candidates.iter().map(|stream| stream.height).min()?;
+            candidates.retain(|stream| stream.height == lowest);
+        }
+    }
+
+    let codec_rank = |stream: &VideoStream| {
+        preference.codecs
+            .iter()
+            .position(|codec| codec == stream.codec_name())
+            .unwrap_or(preference.codecs.len())
+    };
+    let best = candidates
+        .into_iter()
+        .max_by_key(|stream| (stream.qn, std::cmp::Reverse(codec_rank(stream)), stream.bandwidth))?;
+    streams.iter().position(|stream| std::ptr::eq(stream, best))
+}
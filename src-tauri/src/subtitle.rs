@@ -0,0 +1,285 @@
+use std::fs::{self, remove_file, File};
+use std::io::{self, BufWriter, Write};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::CONFIG;
+use crate::mp4::{make_box, make_full_box, Sample, Track};
+use crate::path::{get_sidecar_file_path, get_stream_file_path};
+use crate::wbi;
+
+// 播放器接口，其中的 subtitle.subtitles 为 CC 字幕列表
+const PLAYER_URL: &str = "https://api.bilibili.com/x/player/wbi/v2";
+// tx3g 字幕轨道的时间刻度，与字幕中的秒数对应到毫秒
+const TEXT_TIMESCALE: u32 = 1000;
+
+// 一种语言的 CC 字幕，lan 为语言代码，例如 zh-CN、en-US 和 ai-zh
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Subtitle {
+    pub(crate) lan: String,
+    #[serde(default)]
+    pub(crate) lan_doc: String,
+    #[serde(rename(deserialize = "subtitle_url"))]
+    pub(crate) url: String,
+}
+
+// 字幕中的一句，时间单位为秒
+#[derive(Debug, Deserialize, Clone)]
+pub struct Cue {
+    pub(crate) from: f64,
+    pub(crate) to: f64,
+    pub(crate) content: String,
+}
+
+// 合并时作为软字幕写入的轨道，srt 为已保存的字幕文件，供 ffmpeg 使用
+#[derive(Debug, Clone)]
+pub struct SubtitleTrack {
+    pub(crate) lan: String,
+    pub(crate) srt: String,
+    pub(crate) cues: Vec<Cue>,
+}
+
+// 获取分 P 或剧集的字幕列表，未登录时部分字幕不会返回
+pub async fn get_subtitles(bvid: &str, cid: &str) -> Result<Vec<Subtitle>, String> {
+    let response = wbi::get_json(PLAYER_URL, &[("bvid", bvid.to_string()), ("cid", cid.to_string())]).await?;
+    if response["code"].as_i64() != Some(0) {
+        return Err(format!("failed to get subtitles of {} {}: {} {}", bvid, cid, response["code"], response["message"]));
+    }
+
+    let mut subtitles: Vec<Subtitle> = serde_json::from_value(response["data"]["subtitle"]["subtitles"].clone())
+        .unwrap_or_default();
+    for subtitle in &mut subtitles {
+        // 链接省略了协议
+        if subtitle.url.starts_with("//") {
+            subtitle.url = format!("https:{}", subtitle.url);
+        }
+    }
+    subtitles.retain(|subtitle| !subtitle.url.is_empty());
+    Ok(subtitles)
+}
+
+// 按语言代码下载字幕，保存为与视频同名的 .<lan>.srt 和 .<lan>.vtt，返回需要写入视频的字幕轨道
+// 写入软字幕时 ffmpeg 需要读取 SRT 文件，因此总是保存 SRT
+pub async fn save_subtitles(bvid: &str, cid: &str, lans: &[String], file_path: &str) -> Result<Vec<SubtitleTrack>, String> {
+    let config = CONFIG.lock().unwrap().subtitle.clone();
+    let subtitles = get_subtitles(bvid, cid).await?;
+
+    let mut tracks = Vec::new();
+    for lan in lans {
+        let Some(subtitle) = subtitles.iter().find(|subtitle| &subtitle.lan == lan) else {
+            eprintln!("No {} subtitle for {} {}", lan, bvid, cid);
+            continue;
+        };
+        // 一种语言获取失败时不影响其他语言
+        let cues = match get_cues(&subtitle.url).await {
+            Ok(cues) => cues,
+            Err(e) => {
+                eprintln!("Failed to get {} subtitle for {} {}: {}", lan, bvid, cid, e);
+                continue;
+            }
+        };
+
+        let srt = get_sidecar_file_path(file_path, &format!("{}.srt", lan));
+        if config.srt || config.embed {
+            fs::write(&srt, to_srt(&cues)).map_err(|e| format!("failed to write {}: {}", srt, e))?;
+        }
+        if config.vtt {
+            let vtt = get_sidecar_file_path(file_path, &format!("{}.vtt", lan));
+            fs::write(&vtt, to_vtt(&cues)).map_err(|e| format!("failed to write {}: {}", vtt, e))?;
+        }
+        if config.embed {
+            tracks.push(SubtitleTrack { lan: lan.clone(), srt, cues });
+        }
+    }
+    Ok(tracks)
+}
+
+// 字幕文件是 JSON，body 中按时间排列每一句
+pub async fn get_cues(url: &str) -> Result<Vec<Cue>, String> {
+    let json: Value = reqwest::Client::new()
+        .get(url)
+        .headers(wbi::api_headers())
+        .send()
+        .await
+        .map_err(|e| format!("request subtitle {} failed: {}", url, e))?
+        .json()
+        .await
+        .map_err(|e| format!("parse subtitle {} failed: {}", url, e))?;
+
+    let mut cues: Vec<Cue> = serde_json::from_value(json["body"].clone())
+        .map_err(|e| format!("parse subtitle {} failed: {}", url, e))?;
+    cues.sort_by(|a, b| a.from.total_cmp(&b.from));
+    Ok(cues)
+}
+
+fn cue_time(seconds: f64, separator: char) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as i64;
+    format!("{:02}:{:02}:{:02}{}{:03}", millis / 3600000, millis / 60000 % 60, millis / 1000 % 60, separator, millis % 1000)
+}
+
+pub fn to_srt(cues: &[Cue]) -> String {
+    cues.iter()
+        .enumerate()
+        .map(|(i, cue)| format!("{}\n{} --> {}\n{}\n", i + 1, cue_time(cue.from, ','), cue_time(cue.to, ','), cue.content))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn to_vtt(cues: &[Cue]) -> String {
+    let mut vtt = "WEBVTT\n".to_string();
+    for cue in cues {
+        // WebVTT 中的 --> 不能出现在文字里
+        vtt.push_str(&format!("\n{} --> {}\n{}\n", cue_time(cue.from, '.'), cue_time(cue.to, '.'), cue.content.replace("-->", "->")));
+    }
+    vtt
+}
+
+// 语言代码转换为 ISO 639-2，容器中的语言字段使用三个字母
+pub fn iso_language(lan: &str) -> &'static str {
+    let lan = lan.trim_start_matches("ai-");
+    match lan.split('-').next().unwrap_or_default() {
+        "zh" => "chi",
+        "en" => "eng",
+        "ja" => "jpn",
+        "ko" => "kor",
+        "es" => "spa",
+        "fr" => "fre",
+        "de" => "ger",
+        "ru" => "rus",
+        "pt" => "por",
+        "it" => "ita",
+        "th" => "tha",
+        "vi" => "vie",
+        "id" => "ind",
+        "ar" => "ara",
+        _ => "und",
+    }
+}
+
+// mdhd 中的语言，每个字母减去 0x60 后占 5 位
+fn packed_language(lan: &str) -> [u8; 2] {
+    let code = iso_language(lan)
+        .bytes()
+        .fold(0u16, |code, c| code << 5 | (c - 0x60) as u16);
+    code.to_be_bytes()
+}
+
+// 3GPP 文本的样本描述，字幕居中显示在底部
+fn tx3g_stsd() -> Vec<u8> {
+    let font = b"Sans-Serif";
+    let mut entry = vec![0u8; 6];
+    entry.extend_from_slice(&1u16.to_be_bytes());
+    entry.extend_from_slice(&0u32.to_be_bytes());
+    entry.push(1);
+    entry.push(0xff);
+    entry.extend_from_slice(&[0u8; 4]);
+    // 默认文本框
+    entry.extend_from_slice(&[0u8; 8]);
+    // 默认样式：字体 1、不加粗、字号 18、白色
+    entry.extend_from_slice(&[0, 0, 0, 0, 0, 1, 0, 18, 0xff, 0xff, 0xff, 0xff]);
+    let mut ftab = 1u16.to_be_bytes().to_vec();
+    ftab.extend_from_slice(&1u16.to_be_bytes());
+    ftab.push(font.len() as u8);
+    ftab.extend_from_slice(font);
+    entry.extend(make_box(b"ftab", &ftab));
+
+    let mut stsd = 1u32.to_be_bytes().to_vec();
+    stsd.extend(make_box(b"tx3g", &entry));
+    make_full_box(b"stsd", 0, 0, &stsd)
+}
+
+// tkhd 末尾 60 字节：保留字段、layer、备用组、音量、矩阵和宽高
+fn text_tkhd_tail() -> Vec<u8> {
+    let mut tail = vec![0u8; 16];
+    for value in [0x00010000u32, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000] {
+        tail.extend_from_slice(&value.to_be_bytes());
+    }
+    tail.extend_from_slice(&[0u8; 8]);
+    tail
+}
+
+// 将字幕写为 tx3g 样本，每个样本为两字节长度加 UTF-8 文字，句子之间的空白用空样本填充
+fn write_text_track(cues: &[Cue], lan: &str, path: &str) -> io::Result<Track> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let mut samples = Vec::new();
+    let mut offset = 0u64;
+    let mut time = 0u64;
+    let mut push = |writer: &mut BufWriter<File>, text: &str, duration: u64| -> io::Result<()> {
+        // 长度超过 u16 时在字符边界处截断，避免截断后不是有效的 UTF-8
+        let mut length = text.len().min(u16::MAX as usize);
+        while !text.is_char_boundary(length) {
+            length -= 1;
+        }
+        writer.write_all(&(length as u16).to_be_bytes())?;
+        writer.write_all(&text.as_bytes()[..length])?;
+        samples.push(Sample {
+            offset,
+            size: length as u32 + 2,
+            duration: duration as u32,
+            cts_offset: 0,
+            sync: true,
+        });
+        offset += length as u64 + 2;
+        Ok(())
+    };
+
+    for cue in cues {
+        // 重叠的句子从上一句结束时开始
+        let from = ((cue.from * TEXT_TIMESCALE as f64).round() as u64).max(time);
+        let to = (cue.to * TEXT_TIMESCALE as f64).round() as u64;
+        if to <= from {
+            continue;
+        }
+        if from > time {
+            push(&mut writer, "", from - time)?;
+        }
+        push(&mut writer, &cue.content, to - from)?;
+        time = to;
+    }
+    writer.flush()?;
+
+    let mut hdlr = vec![0u8; 4];
+    hdlr.extend_from_slice(b"sbtl");
+    hdlr.extend_from_slice(&[0u8; 12]);
+    hdlr.extend_from_slice(b"SubtitleHandler\0");
+
+    Ok(Track {
+        path: path.to_string(),
+        handler: *b"sbtl",
+        timescale: TEXT_TIMESCALE,
+        language: packed_language(lan),
+        tkhd_tail: text_tkhd_tail(),
+        hdlr: make_full_box(b"hdlr", 0, 0, &hdlr),
+        media_header: make_full_box(b"nmhd", 0, 0, &[]),
+        dinf: Vec::new(),
+        stsd: tx3g_stsd(),
+        samples,
+    })
+}
+
+// 在输出文件旁边生成各个字幕轨道的临时文件，没有内容的字幕跳过
+pub fn text_tracks(subtitles: &[SubtitleTrack], output: &str) -> Result<Vec<Track>, String> {
+    let mut tracks = Vec::new();
+    for subtitle in subtitles {
+        let path = get_stream_file_path(output, &format!("subtitle_{}", subtitle.lan));
+        let track = write_text_track(&subtitle.cues, &subtitle.lan, &path);
+        match track {
+            Ok(track) if !track.samples.is_empty() => tracks.push(track),
+            Ok(track) => remove_text_tracks(&[track]),
+            Err(e) => {
+                remove_text_tracks(&tracks);
+                return Err(format!("failed to write {}: {}", path, e));
+            }
+        }
+    }
+    Ok(tracks)
+}
+
+pub fn remove_text_tracks(tracks: &[Track]) {
+    for track in tracks {
+        if let Err(e) = remove_file(&track.path) {
+            eprintln!("Failed to delete {}: {}", track.path, e);
+        }
+    }
+}
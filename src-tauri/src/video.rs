@@ -1,14 +1,18 @@
-use regex::Regex;
-use reqwest::header::{COOKIE, HeaderValue, USER_AGENT};
-use scraper::{Html, Selector};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::Agent;
-use crate::config::CONFIG;
+use crate::resolver::VideoId;
+use crate::stream::{Dash, VideoStream};
+use crate::subtitle::{get_subtitles, Subtitle};
+use crate::wbi;
 
-const VIDEO_INFO_URL: &str = "https://api.bilibili.com/x/web-interface/wbi/view?bvid={}";
-const VIDEO_PLAY_URL: &str = "https://www.bilibili.com/video/bvid/?p={} ";
+const VIDEO_INFO_URL: &str = "https://api.bilibili.com/x/web-interface/wbi/view";
+const VIDEO_STREAM_URL: &str = "https://api.bilibili.com/x/player/wbi/playurl";
+
+// fnval 的各个标志位：16 dash、64 HDR、128 4K、256 杜比音频、512 杜比视界、1024 8K、2048 AV1
+const FNVAL: i64 = 16 | 64 | 128 | 256 | 512 | 1024 | 2048;
+// 请求可用的最高清晰度
+const MAX_QN: i64 = 127;
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Video {
@@ -17,6 +21,7 @@ pub struct Video {
     cid: String,
     cover: String,
     bvid: String,
+    aid: u64,
     author: String,
     author_avatar: String,
     count: usize,
@@ -25,62 +30,59 @@ pub struct Video {
     danmaku: String,
     episodes: Vec<Episode>,
     formats: Vec<String>,
+    // 链接中 ?p= 指定的分 P，从 1 开始，前端据此默认选中
+    pub(crate) page: Option<u32>,
+}
+
+// playurl 接口的返回值
+#[derive(Debug, Deserialize)]
+struct PlayUrlResponse {
+    code: i64,
+    #[serde(default)]
+    message: String,
+    data: Option<PlayUrl>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Deserialize, Default)]
+pub struct PlayUrl {
+    #[serde(default)]
+    pub(crate) accept_description: Vec<String>,
+    #[serde(default)]
+    pub(crate) dash: Dash,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Episode {
     bvid: String,
     ep_id: String,
     cid: String,
     title: String,
-    video_urls: Vec<String>,
-    audio_url: String,
+    // dash 中的视频流，包括清晰度、编码和备用 CDN 链接
+    pub(crate) video_streams: Vec<VideoStream>,
+    pub(crate) audio_url: String,
+    pub(crate) audio_backup_urls: Vec<String>,
+    // 仅下载音频时使用的最高音质音频流，格式为 flac 或 m4a
+    pub(crate) best_audio_url: String,
+    pub(crate) best_audio_backup_urls: Vec<String>,
+    pub(crate) best_audio_format: String,
+    // CC 字幕列表，包括语言代码和字幕文件链接
+    pub(crate) subtitles: Vec<Subtitle>,
     duration: i32,
     cover: String,
     play: String,
     danmaku: String,
-    sizes: Vec<String>,
+    // 与 video_streams 一一对应的估算大小，按码率和时长计算，不是实际的文件大小
+    pub(crate) sizes: Vec<String>,
 }
 
-pub async fn get_video_info(bvid: &str) -> Result<Video, String> {
-    let config = CONFIG.lock().unwrap();
-    let cookie = config.cookie.clone();
-
-    let client = reqwest::Client::new();
-    let mut global_headers = reqwest::header::HeaderMap::new();
-    global_headers.insert(USER_AGENT, HeaderValue::from_static(Agent));
-    global_headers.insert(COOKIE, HeaderValue::from_str(&cookie).unwrap());
-
-    // 创建一个 Surf 客户端
-    let surf_client = surf::client();
-    let video_play_url = VIDEO_PLAY_URL.replace("bvid", bvid);
-    // 发送带 Cookie 的请求
-    let mut response1 = surf_client
-        .get(&video_play_url)
-        .header("Cookie", cookie.as_str())
-        .header("User-Agent", Agent)
-        .await.unwrap();
-
-    // 获取响应体
-    let html = response1.body_string().await.unwrap();
-    let document = Html::parse_document(&html);
-    // 获取视频名称和描述
-    let meta_description_selector = Selector::parse(r#"meta[name="description"]"#).unwrap();
-    let title_selector = Selector::parse("title").unwrap();
-    let title_element = document.select(&title_selector).next().ok_or("Title not found")?;
-    let title = title_element.text().collect::<String>();
-    let description = document
-        .select(&meta_description_selector)
-        .next()
-        .map(|node| node.value().attr("content").unwrap_or(""))
-        .unwrap().to_string();
-
+pub async fn get_video_info(id: &VideoId) -> Result<Video, String> {
     let mut video = Video {
-        title,
-        description,
+        title: String::new(),
+        description: String::new(),
         cid: String::new(),
         cover: String::new(),
-        bvid: bvid.to_string(),
+        bvid: id.bvid()?,
+        aid: id.aid()?,
         author: String::new(),
         author_avatar: String::new(),
         count: 0,
@@ -89,14 +91,19 @@ pub async fn get_video_info(bvid: &str) -> Result<Video, String> {
         danmaku: String::new(),
         episodes: vec![],
         formats: vec![],
+        page: None,
     };
 
     // 请求视频信息 API 接口，拼装信息
-    let video_info_url = format!("https://api.bilibili.com/x/web-interface/view?bvid={}", bvid);
-    let ret = client.get(&video_info_url).headers(global_headers.clone()).send().await.unwrap();
-    let video_info: Value = client.get(&video_info_url).headers(global_headers.clone()).send().await.unwrap().json().await.unwrap();
+    let video_info = wbi::get_json(VIDEO_INFO_URL, &[("bvid", video.bvid.clone())]).await?;
+    if video_info["code"].as_i64() != Some(0) {
+        return Err(format!("failed to get video info of {}: {} {}", video.bvid, video_info["code"], video_info["message"]));
+    }
 
     if let Some(data) = video_info.get("data") {
+        // 标题和简介同样从接口中获取，不再解析视频页面
+        video.title = data.get("title").and_then(Value::as_str).unwrap_or_default().to_string();
+        video.description = data.get("desc").and_then(Value::as_str).unwrap_or_default().to_string();
         video.cid = data.get("cid").and_then(Value::as_i64)
             .map(|num| num.to_string())
             .unwrap_or_default();
@@ -129,9 +136,11 @@ pub async fn get_video_info(bvid: &str) -> Result<Video, String> {
                     cover: "".to_string(),
                     play: "".to_string(),
                     danmaku: "".to_string(),
-                    video_urls: vec![],
+                    video_streams: vec![],
                     audio_url: String::new(),
+                    audio_backup_urls: vec![],
                     sizes: vec![],
+                    ..Default::default()
                 };
 
                 episodes.push(episode);
@@ -140,50 +149,89 @@ pub async fn get_video_info(bvid: &str) -> Result<Video, String> {
         video.episodes = episodes;
     }
 
-    // 获取视频格式和链接信息
-    for j in 0..video.count {
+    // 字幕列表和链接信息同时获取，不随分 P 数量逐个等待
+    let bvid = video.bvid.clone();
+    let subtitle_tasks: Vec<_> = video.episodes.iter()
+        .map(|episode| {
+            let (bvid, cid) = (bvid.clone(), episode.cid.clone());
+            tokio::spawn(async move { get_subtitles(&bvid, &cid).await })
+        })
+        .collect();
+
+    // 每个分 P 使用自己的 cid 获取视频格式和链接信息
+    for j in 0..video.episodes.len() {
         let episode = &mut video.episodes[j];
-        let mut html = surf_client
-            .get(&video_play_url)
-            .header("Cookie", cookie.as_str())
-            .header("User-Agent", Agent)
-            .await.unwrap()
-            .body_string()
-            .await.unwrap();
-        let re = Regex::new(r#"<script>window\.__playinfo__=([^<]*)</script>"#).unwrap();
-        if let Some(captures) = re.captures(&html) {
-            // 提取匹配的内容
-            if let Some(matched) = captures.get(1) {
-                let play_info: Value = serde_json::from_str(matched.as_str()).unwrap();
-                let data = &play_info["data"];
-
-                // 获取视频链接
-                let dash = data.get("dash");
-                let videos = dash.and_then(|d| d.get("video")).and_then(Value::as_array).unwrap();
-                let mut video_urls = vec![];
-                for video in videos {
-                    if let Some(base_url) = video.get("baseUrl").and_then(Value::as_str) {
-                        video_urls.push(base_url.to_string());
-                    }
-                }
-                episode.video_urls = video_urls;
-
-                // 获取音频链接
-                if let Some(audio) = data.get("dash").and_then(|dash| dash.get("audio")).and_then(Value::as_array).and_then(|audios| audios.get(0)) {
-                    episode.audio_url = audio.get("baseUrl").and_then(Value::as_str).unwrap_or_default().to_string();
-                }
-
-                // 获取格式信息
-                if j == 0 {
-                    if let Some(formats) = data.get("accept_description").and_then(Value::as_array) {
-                        video.formats = formats.iter().filter_map(Value::as_str).map(|s| s.to_string()).collect();
-                    }
-                }
+        let play_url = match get_play_url(&bvid, &episode.cid).await {
+            Ok(play_url) => play_url,
+            Err(e) => {
+                eprintln!("Failed to get streams of {} p{}: {}", bvid, j + 1, e);
+                continue;
             }
-        } else {
-            println!("No match found");
+        };
+
+        // 获取音视频链接
+        fill_streams(&play_url.dash, episode);
+
+        // 获取格式信息
+        if video.formats.is_empty() {
+            video.formats = play_url.accept_description;
+        }
+    }
+
+    // 获取字幕列表失败时只是没有字幕可选
+    for (j, task) in subtitle_tasks.into_iter().enumerate() {
+        match task.await.unwrap_or_else(|e| Err(e.to_string())) {
+            Ok(subtitles) => video.episodes[j].subtitles = subtitles,
+            Err(e) => eprintln!("Failed to get subtitles of {} p{}: {}", bvid, j + 1, e),
         }
     }
 
     Ok(video)
 }
+
+// 填入 dash 中的视频流及大小、默认音频流和最高音质的音频流
+pub(crate) fn fill_streams(dash: &Dash, episode: &mut Episode) {
+    episode.video_streams = dash.video.clone();
+    // 与 video_streams 一一对应，playurl 接口不返回文件大小，按码率估算
+    episode.sizes = episode.video_streams.iter().map(|stream| stream.estimated_size(dash.duration).to_string()).collect();
+    if let Some(audio) = dash.audio.first() {
+        episode.audio_url = audio.base_url.clone();
+        episode.audio_backup_urls = audio.backup_urls.clone();
+    }
+    if let Some((audio, format)) = dash.best_audio() {
+        episode.best_audio_url = audio.base_url.clone();
+        episode.best_audio_backup_urls = audio.backup_urls.clone();
+        episode.best_audio_format = format.to_string();
+    }
+}
+
+// 请求分 P 的 playurl 接口，fnval 要求返回 dash 格式以及 HDR、4K、杜比、8K 和 AV1 的流
+pub async fn get_play_url(bvid: &str, cid: &str) -> Result<PlayUrl, String> {
+    let response: PlayUrlResponse = wbi::get(VIDEO_STREAM_URL, &[
+        ("bvid", bvid.to_string()),
+        ("cid", cid.to_string()),
+        ("qn", MAX_QN.to_string()),
+        ("fnval", FNVAL.to_string()),
+        ("fnver", "0".to_string()),
+        ("fourk", "1".to_string()),
+    ]).await?;
+
+    match response.data {
+        Some(play_url) if response.code == 0 => Ok(play_url),
+        _ => Err(format!("no dash streams for {} {}: {} {}", bvid, cid, response.code, response.message)),
+    }
+}
+
+// 根据 bvid 和 cid 重新获取分 P 的音视频链接，用于刷新过期的下载链接
+pub async fn get_episode_streams(bvid: &str, cid: &str) -> Result<Episode, String> {
+    let play_url = get_play_url(bvid, cid).await?;
+
+    let mut episode = Episode {
+        bvid: bvid.to_string(),
+        cid: cid.to_string(),
+        ..Default::default()
+    };
+    fill_streams(&play_url.dash, &mut episode);
+
+    Ok(episode)
+}
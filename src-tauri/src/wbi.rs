@@ -0,0 +1,153 @@
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+use lazy_static::lazy_static;
+use reqwest::header::{COOKIE, HeaderMap, HeaderValue, USER_AGENT};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tokio::sync::Mutex;
+
+use crate::Agent;
+use crate::config::CONFIG;
+
+const NAV_URL: &str = "https://api.bilibili.com/x/web-interface/nav";
+// 密钥每天更换，缓存一段时间后重新获取
+const KEY_TTL: Duration = Duration::from_secs(60 * 60);
+// 签名错误或者密钥过期时接口返回的错误码
+const CODE_RISK_CONTROL: i64 = -352;
+
+// 缓存的 mixin key 和获取的时间
+struct CachedKey {
+    mixin_key: String,
+    fetched: Instant,
+}
+
+lazy_static! {
+    static ref MIXIN_KEY: Mutex<Option<CachedKey>> = Mutex::new(None);
+}
+
+// 从 img_key 和 sub_key 拼接的字符串中依次取出的位置，取前 32 位作为 mixin key
+const MIXIN_KEY_ENC_TAB: [usize; 64] = [
+    46, 47, 18, 2, 53, 8, 23, 32, 15, 50, 10, 31, 58, 3, 45, 35, 27, 43, 5, 49, 33, 9, 42, 19, 29, 28, 14, 39, 12, 38,
+    41, 13, 37, 48, 7, 16, 24, 55, 40, 61, 26, 17, 0, 1, 60, 51, 30, 4, 22, 25, 54, 21, 56, 59, 6, 63, 57, 62, 11, 36,
+    20, 34, 44, 52,
+];
+
+// 请求头中带上设置里的 cookie
+pub fn api_headers() -> HeaderMap {
+    let cookie = CONFIG.lock().unwrap().cookie.clone();
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, HeaderValue::from_static(Agent));
+    if let Ok(cookie) = HeaderValue::from_str(&cookie) {
+        headers.insert(COOKIE, cookie);
+    }
+    headers
+}
+
+// 链接中文件名去掉扩展名的部分
+fn key_from_url(url: &str) -> Option<String> {
+    let file = url.rsplit('/').next()?;
+    Some(file.split('.').next()?.to_string())
+}
+
+// 未登录时 nav 接口返回 -101，但仍然会给出 wbi_img
+pub async fn get_wbi_keys() -> Result<(String, String), String> {
+    let nav: Value = reqwest::Client::new()
+        .get(NAV_URL)
+        .headers(api_headers())
+        .send()
+        .await
+        .map_err(|e| format!("request nav failed: {}", e))?
+        .json()
+        .await
+        .map_err(|e| format!("parse nav failed: {}", e))?;
+
+    let wbi_img = &nav["data"]["wbi_img"];
+    let img_key = wbi_img["img_url"].as_str().and_then(key_from_url);
+    let sub_key = wbi_img["sub_url"].as_str().and_then(key_from_url);
+    match (img_key, sub_key) {
+        (Some(img_key), Some(sub_key)) => Ok((img_key, sub_key)),
+        _ => Err(format!("no wbi keys in nav response: {}", nav["message"])),
+    }
+}
+
+pub fn mixin_key(img_key: &str, sub_key: &str) -> String {
+    let raw = format!("{}{}", img_key, sub_key).into_bytes();
+    MIXIN_KEY_ENC_TAB
+        .iter()
+        .filter_map(|&index| raw.get(index).map(|&c| c as char))
+        .take(32)
+        .collect()
+}
+
+// 参数按名称排序，去掉值中的 !'()* 后编码，加上 wts 和 w_rid 组成查询字符串
+pub fn sign(params: &[(&str, String)], mixin_key: &str, wts: i64) -> String {
+    let mut params = params
+        .iter()
+        .map(|(key, value)| (key.to_string(), value.chars().filter(|c| !"!'()*".contains(*c)).collect::<String>()))
+        .collect::<Vec<_>>();
+    params.push(("wts".to_string(), wts.to_string()));
+    params.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let query = params
+        .iter()
+        .map(|(key, value)| format!("{}={}", urlencoding::encode(key), urlencoding::encode(value)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let w_rid = format!("{:x}", md5::compute(format!("{}{}", query, mixin_key)));
+
+    format!("{}&w_rid={}", query, w_rid)
+}
+
+// 返回缓存的 mixin key，过期或者没有时重新获取
+async fn cached_mixin_key() -> Result<String, String> {
+    let mut cached = MIXIN_KEY.lock().await;
+    if let Some(key) = cached.as_ref().filter(|key| key.fetched.elapsed() < KEY_TTL) {
+        return Ok(key.mixin_key.clone());
+    }
+
+    let (img_key, sub_key) = get_wbi_keys().await?;
+    let key = mixin_key(&img_key, &sub_key);
+    *cached = Some(CachedKey {
+        mixin_key: key.clone(),
+        fetched: Instant::now(),
+    });
+    Ok(key)
+}
+
+async fn clear_cached_key() {
+    *MIXIN_KEY.lock().await = None;
+}
+
+pub async fn sign_query(params: &[(&str, String)]) -> Result<String, String> {
+    Ok(sign(params, &cached_mixin_key().await?, Utc::now().timestamp()))
+}
+
+async fn request_json(url: &str, params: &[(&str, String)]) -> Result<Value, String> {
+    let query = sign_query(params).await?;
+    reqwest::Client::new()
+        .get(format!("{}?{}", url, query))
+        .headers(api_headers())
+        .send()
+        .await
+        .map_err(|e| format!("request {} failed: {}", url, e))?
+        .json()
+        .await
+        .map_err(|e| format!("parse response of {} failed: {}", url, e))
+}
+
+// 签名后请求接口，签名被拒绝时重新获取密钥再试一次
+pub async fn get_json(url: &str, params: &[(&str, String)]) -> Result<Value, String> {
+    let response = request_json(url, params).await?;
+    if response["code"].as_i64() != Some(CODE_RISK_CONTROL) {
+        return Ok(response);
+    }
+
+    clear_cached_key().await;
+    request_json(url, params).await
+}
+
+pub async fn get<T: DeserializeOwned>(url: &str, params: &[(&str, String)]) -> Result<T, String> {
+    let response = get_json(url, params).await?;
+    serde_json::from_value(response).map_err(|e| format!("parse response of {} failed: {}", url, e))
+}
@@ -8,10 +8,82 @@ export interface BiliConfig {
   save_path: string;
   agent: string;
   cookie: string;
+  segment_count: number;
+  mirror_min_speed: number;
+  retry: RetryPolicy;
+  speed_limit: number;
+  max_concurrent: number;
+  schedule: Schedule;
+  min_free_space: number;
+  ffmpeg_path: string;
+  container: string;
+  embed_metadata: boolean;
+  quality: QualityPreference;
+  danmaku: DanmakuConfig;
+  subtitle: SubtitleConfig;
 }
 
+export interface QualityPreference {
+  max_height: number;
+  codecs: string[];
+}
+
+export interface DanmakuConfig {
+  enabled: boolean;
+  full: boolean;
+  ass: boolean;
+  font_size: number;
+  opacity: number;
+  density: number;
+  scroll_duration: number;
+}
+
+export interface SubtitleConfig {
+  srt: boolean;
+  vtt: boolean;
+  embed: boolean;
+}
+
+export interface MergeBackend {
+  name: string;
+  path: string;
+  available: boolean;
+}
+
+export interface Schedule {
+  enabled: boolean;
+  windows: TimeWindow[];
+}
+
+export interface TimeWindow {
+  weekday: number;
+  start: string;
+  end: string;
+}
+
+export interface RetryPolicy {
+  max_attempts: number;
+  base_delay: number;
+  max_delay: number;
+  jitter: number;
+  retry_statuses: number[];
+}
+
+export type VideoId = { bvid: string } | { aid: number };
+
+export type Target =
+  | { type: "video"; id: VideoId; page: number | null }
+  | { type: "episode"; ep_id: number }
+  | { type: "season"; season_id: number }
+  | { type: "media"; media_id: number }
+  | { type: "space"; mid: number }
+  | { type: "favorite"; media_id: number }
+  | { type: "collection"; mid: number; season_id: number }
+  | { type: "series"; mid: number; series_id: number };
+
 export interface Video {
   bvid: string;
+  aid: number;
   cid: string;
   title: string;
   description: string;
@@ -25,21 +97,37 @@ export interface Video {
   formats: string[];
   date: string,
   episodes: Episode[];
+  // 链接中 ?p= 指定的分 P，从 1 开始
+  page: number | null;
 }
 
 export interface Episode {
   epId: string;
+  ep_id: string;
+  bvid: string;
   cid: string;
   title: string;
-  video_urls: string[];
+  video_streams: VideoStream[];
   audio_url: string;
+  audio_backup_urls: string[];
+  best_audio_url: string;
+  best_audio_backup_urls: string[];
+  best_audio_format: "flac" | "m4a" | "";
+  subtitles: Subtitle[];
   duration: number;
   cover: string;
   play: string;
   danmaku: string;
+  // 与 video_streams 一一对应的估算大小（字节），按码率和时长计算
   sizes: string[];
 }
 
+export interface Subtitle {
+  lan: string;
+  lan_doc: string;
+  url: string;
+}
+
 export interface Anime {
   title: string;
   types: string;
@@ -51,6 +139,21 @@ export interface Anime {
   score: string;
   episodes: Episode[];
   formats: string[];
+  sections: Section[];
+  seasons: Season[];
+}
+
+export interface Section {
+  title: string;
+  episodes: Episode[];
+}
+
+export interface Season {
+  season_id: string;
+  media_id: string;
+  title: string;
+  cover: string;
+  badge: string;
 }
 
 export interface Download {
@@ -67,9 +170,58 @@ export interface Download {
   status: string;
   added_date: string;
   last_updated_date: string;
+  bvid: string;
+  cid: string;
+  ep_id: string;
+  quality: number;
+  video_backup_urls: string[];
+  audio_backup_urls: string[];
+  speed_limit?: number | null;
+  queue_position?: number;
+  priority?: number;
+  start_at?: string;
+  container?: string;
+  metadata?: Metadata;
+  qn?: number;
+  codecid?: number;
+  subtitles?: string[];
+}
+
+export interface VideoStream {
+  qn: number;
+  codecid: number;
+  codecs: string;
+  width: number;
+  height: number;
+  frame_rate: string;
+  bandwidth: number;
+  base_url: string;
+  backup_urls: string[];
+  size: number;
+}
+
+export interface Metadata {
+  title: string;
+  artist: string;
+  date: string;
+  description: string;
+  cover: string;
+}
+
+export interface DownloadStatus {
+  id: number;
+  status: string;
+  attempt: number;
+  message: string;
 }
 
 export interface DownloadProgress {
   id: number,
-  chunk_length: number
+  chunk_length: number,
+  speed: number,
+  average_speed: number,
+  eta: number | null,
+  phase: "video" | "audio" | "merging" | "completed" | "",
+  video_downloaded: number,
+  audio_downloaded: number
 }
\ No newline at end of file
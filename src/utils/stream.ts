@@ -0,0 +1,45 @@
+import {Episode, Subtitle, VideoStream} from "../types";
+
+export interface QualityOption {
+  label: string;
+  qn: number;
+  codecid: number;
+}
+
+const CODEC_NAMES: Record<number, string> = {7: "AVC", 12: "HEVC", 13: "AV1"};
+
+export function streamLabel(stream: VideoStream): string {
+  const fps = Math.round(Number(stream.frame_rate));
+  const resolution = `${stream.height}P${fps > 30 ? fps : ""}`;
+  return `${resolution} ${CODEC_NAMES[stream.codecid] ?? stream.codecs}`;
+}
+
+// 第一项为按设置自动选择，其余按清晰度从高到低排列
+// sizes 与 streams 一一对应，是按码率和时长估算的大小，标注为约数
+export function qualityOptions(streams: VideoStream[], sizes: string[] = []): QualityOption[] {
+  const options: QualityOption[] = [{label: "自动", qn: 0, codecid: 0}];
+  streams
+    .map((stream, index) => ({stream, size: Number(sizes[index] ?? 0)}))
+    .sort((a, b) => b.stream.qn - a.stream.qn || a.stream.codecid - b.stream.codecid)
+    .forEach(({stream, size}) => {
+      if (!options.some(option => option.qn === stream.qn && option.codecid === stream.codecid)) {
+        const estimate = size > 0 ? ` 约${Math.round(size / (1024 * 1024))}MB` : "";
+        options.push({label: `${streamLabel(stream)}${estimate}`, qn: stream.qn, codecid: stream.codecid});
+      }
+    });
+  return options;
+}
+
+
+// 选中剧集的字幕语言并集，按首次出现的顺序排列
+export function subtitleLanguages(episodes: (Episode | undefined)[]): Subtitle[] {
+  const languages: Subtitle[] = [];
+  episodes.forEach(episode => {
+    (episode?.subtitles ?? []).forEach(subtitle => {
+      if (!languages.some(language => language.lan === subtitle.lan)) {
+        languages.push(subtitle);
+      }
+    });
+  });
+  return languages;
+}